@@ -1,9 +1,90 @@
+use crate::utils::read_from_file;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
+    error::Error,
     sync::{Arc, Mutex},
 };
-use teloxide::types::UserId;
+use teloxide::types::{ChatId, UserId};
+
+/// Every chat has its own players.
+pub type Records = BTreeMap<ChatId, BTreeMap<UserId, Record>>;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CasinoFile {
+    PerChat(Records),
+    Legacy(BTreeMap<UserId, Record>),
+}
+
+/// Records saved before the casino was per chat are moved to `legacy_chat`.
+pub fn load(s: &str, legacy_chat: ChatId) -> Result<Records, Box<dyn Error>> {
+    Ok(match read_from_file(s)? {
+        CasinoFile::PerChat(x) => x,
+        CasinoFile::Legacy(x) => BTreeMap::from([(legacy_chat, x)]),
+    })
+}
+
+/// Points and spins of every user summed over all chats.
+pub fn global_records(map: &Records) -> Vec<(UserId, Record)> {
+    let mut users: BTreeMap<UserId, Record> = BTreeMap::new();
+    for (id, x) in map.values().flatten() {
+        let y = users
+            .entry(*id)
+            .or_insert(Record::new(x.user_name.clone(), 0));
+        y.points += x.points;
+        y.tries += x.tries;
+    }
+    users.into_iter().collect()
+}
+
+/// Whether `user` has at least `amount` points in `chat`.
+pub fn can_cover(map: &Records, chat: ChatId, user: UserId, amount: usize) -> bool {
+    amount == 0
+        || map
+            .get(&chat)
+            .and_then(|x| x.get(&user))
+            .is_some_and(|x| x.points >= amount)
+}
+
+/// Takes up to `amount` points from a player and returns how many were taken.
+pub fn fine(map: &mut Records, chat: ChatId, user: UserId, amount: usize) -> usize {
+    match map.get_mut(&chat).and_then(|x| x.get_mut(&user)) {
+        Some(x) => {
+            let amount = amount.min(x.points);
+            x.points -= amount;
+            amount
+        }
+        None => 0,
+    }
+}
+
+/// Gives `amount` points to a player who has a record in `chat`.
+pub fn pay(map: &mut Records, chat: ChatId, user: UserId, amount: usize) {
+    if let Some(x) = map.get_mut(&chat).and_then(|x| x.get_mut(&user)) {
+        x.points += amount;
+    }
+}
+
+/// Moves up to `amount` points from one player to another and returns how many moved.
+pub fn transfer(map: &mut Records, chat: ChatId, from: UserId, to: UserId, amount: usize) -> usize {
+    let records = match map.get_mut(&chat) {
+        Some(x) if x.contains_key(&to) => x,
+        _ => return 0,
+    };
+    let amount = match records.get_mut(&from) {
+        Some(x) => {
+            let amount = amount.min(x.points);
+            x.points -= amount;
+            amount
+        }
+        None => return 0,
+    };
+    records.get_mut(&to).unwrap().points += amount;
+    amount
+}
 
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct Record {
@@ -11,20 +92,44 @@ pub struct Record {
     pub points: usize,
     pub tries: usize,
     pub tries_left: usize,
+    /// Winning spins in a row.
+    #[serde(default)]
+    pub streak: usize,
 }
 impl Record {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, tries: usize) -> Self {
         Self {
             user_name: name,
             points: 0,
             tries: 0,
-            tries_left: 3,
+            tries_left: tries,
+            streak: 0,
+        }
+    }
+    /// Average points per spin.
+    pub fn efficiency(&self) -> f64 {
+        if self.tries == 0 {
+            0.0
+        } else {
+            self.points as f64 / self.tries as f64
         }
     }
-    pub fn spin(&mut self, _points: SlotResult) {
+    /// Adds the points of a spin and returns them.
+    pub fn spin(&mut self, result: SlotResult, rules: &Rules) -> usize {
+        let mut points = result.payout(&rules.payouts);
+        if result == SlotResult::Sevens {
+            points *= rules.jackpot_multiplier;
+        }
+        if points > 0 {
+            self.streak += 1;
+            points += rules.streak_bonus * (self.streak - 1);
+        } else {
+            self.streak = 0;
+        }
         self.tries += 1;
-        self.tries_left -= 1;
-        self.points += usize::from(_points);
+        self.tries_left = self.tries_left.saturating_sub(1);
+        self.points += points;
+        points
     }
 }
 
@@ -39,20 +144,221 @@ impl PartialOrd for Record {
     }
 }
 
-#[derive(Clone, Copy)]
+/// Points for every slot result.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Payouts {
+    pub bars: usize,
+    pub grapes: usize,
+    pub lemons: usize,
+    pub sevens: usize,
+    pub two_sevens: usize,
+    pub pair: usize,
+    pub any_seven: usize,
+}
+
+impl Default for Payouts {
+    fn default() -> Self {
+        Self {
+            bars: 1,
+            grapes: 2,
+            lemons: 3,
+            sevens: 5,
+            two_sevens: 0,
+            pair: 0,
+            any_seven: 0,
+        }
+    }
+}
+
+/// Economy of one chat.
+#[derive(Clone, Debug)]
+pub struct Rules {
+    pub daily_spins: usize,
+    /// Extra points for every winning spin in a row after the first one.
+    pub streak_bonus: usize,
+    /// The sevens triple pays this many times its payout.
+    pub jackpot_multiplier: usize,
+    /// Points every spin pays into the jackpot, 0 keeps spins free and turns it off.
+    pub spin_cost: usize,
+    /// Points a player may give away between two resets.
+    pub transfer_limit: usize,
+    pub payouts: Payouts,
+}
+
+/// Rules of one chat, whatever is left out comes from the `[casino]` section.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatRules {
+    pub chat: i64,
+    pub daily_spins: Option<usize>,
+    pub streak_bonus: Option<usize>,
+    pub jackpot_multiplier: Option<usize>,
+    pub spin_cost: Option<usize>,
+    pub transfer_limit: Option<usize>,
+    pub payouts: Option<Payouts>,
+}
+
+/// `[casino]` section of the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CasinoSettings {
+    /// Local `HH:MM` when everybody gets their daily spins back.
+    pub reset_time: String,
+    /// IANA name like `Europe/Moscow`.
+    pub timezone: String,
+    pub daily_spins: usize,
+    pub streak_bonus: usize,
+    pub jackpot_multiplier: usize,
+    pub spin_cost: usize,
+    pub transfer_limit: usize,
+    pub payouts: Payouts,
+    /// `[[casino.chats]]` tables.
+    pub chats: Vec<ChatRules>,
+}
+
+impl Default for CasinoSettings {
+    fn default() -> Self {
+        Self {
+            reset_time: String::from("00:00"),
+            timezone: String::from("UTC"),
+            daily_spins: 3,
+            streak_bonus: 0,
+            jackpot_multiplier: 1,
+            spin_cost: 0,
+            transfer_limit: 20,
+            payouts: Payouts::default(),
+            chats: vec![],
+        }
+    }
+}
+
+impl CasinoSettings {
+    /// Midnight UTC when the reset time or the timezone does not parse.
+    fn reset_at(&self) -> (NaiveTime, Tz) {
+        let time = NaiveTime::parse_from_str(&self.reset_time, "%H:%M").unwrap_or_else(|e| {
+            log::warn!("bad casino reset_time {}: {e}", self.reset_time);
+            NaiveTime::from_hms_opt(0, 0, 0).unwrap()
+        });
+        let tz = self.timezone.parse().unwrap_or_else(|e| {
+            log::warn!("bad casino timezone {}: {e}", self.timezone);
+            Tz::UTC
+        });
+        (time, tz)
+    }
+
+    fn reset_on(&self, date: NaiveDate) -> DateTime<Utc> {
+        let (time, tz) = self.reset_at();
+        let local = date.and_time(time);
+        // a reset time skipped by a DST change happens an hour later
+        tz.from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                tz.from_local_datetime(&(local + Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|x| x.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+    }
+
+    /// The latest reset that should have happened by `now`.
+    pub fn last_reset(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.with_timezone(&self.reset_at().1).date_naive();
+        let x = self.reset_on(today);
+        if x <= now {
+            x
+        } else {
+            self.reset_on(today - Duration::days(1))
+        }
+    }
+
+    pub fn next_reset(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.with_timezone(&self.reset_at().1).date_naive();
+        let x = self.reset_on(today);
+        if x > now {
+            x
+        } else {
+            self.reset_on(today + Duration::days(1))
+        }
+    }
+
+    pub fn rules(&self, chat: ChatId) -> Rules {
+        let x = self.chats.iter().find(|x| ChatId(x.chat) == chat);
+        Rules {
+            daily_spins: x.and_then(|x| x.daily_spins).unwrap_or(self.daily_spins),
+            streak_bonus: x.and_then(|x| x.streak_bonus).unwrap_or(self.streak_bonus),
+            jackpot_multiplier: x
+                .and_then(|x| x.jackpot_multiplier)
+                .unwrap_or(self.jackpot_multiplier),
+            spin_cost: x.and_then(|x| x.spin_cost).unwrap_or(self.spin_cost),
+            transfer_limit: x
+                .and_then(|x| x.transfer_limit)
+                .unwrap_or(self.transfer_limit),
+            payouts: x
+                .and_then(|x| x.payouts.clone())
+                .unwrap_or_else(|| self.payouts.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reel {
+    Bar,
+    Grapes,
+    Lemon,
+    Seven,
+}
+
+/// The reels of a slot machine dice value, left to right.
+/// Telegram counts the values in base 4 from 1, the left reel being the lowest digit.
+pub fn decode(value: i32) -> Option<[Reel; 3]> {
+    if !(1..=64).contains(&value) {
+        return None;
+    }
+    let reel = |x: i32| match x % 4 {
+        0 => Reel::Bar,
+        1 => Reel::Grapes,
+        2 => Reel::Lemon,
+        _ => Reel::Seven,
+    };
+    let x = value - 1;
+    Some([reel(x), reel(x / 4), reel(x / 16)])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SlotResult {
     Bars,
     Grapes,
     Lemons,
     Sevens,
+    /// Two sevens and anything else.
+    TwoSevens,
+    /// Two of the same other symbol.
+    Pair,
+    /// A single seven among different symbols.
+    AnySeven,
     Nothing,
 }
 
-pub fn refresh_tries(_map: Arc<Mutex<BTreeMap<UserId, Record>>>) {
-    _map.lock()
-        .unwrap()
-        .values_mut()
-        .for_each(|x| x.tries_left = 3);
+impl SlotResult {
+    pub fn payout(self, payouts: &Payouts) -> usize {
+        match self {
+            SlotResult::Bars => payouts.bars,
+            SlotResult::Grapes => payouts.grapes,
+            SlotResult::Lemons => payouts.lemons,
+            SlotResult::Sevens => payouts.sevens,
+            SlotResult::TwoSevens => payouts.two_sevens,
+            SlotResult::Pair => payouts.pair,
+            SlotResult::AnySeven => payouts.any_seven,
+            SlotResult::Nothing => 0,
+        }
+    }
+}
+
+pub fn refresh_tries(_map: Arc<Mutex<Records>>, settings: &CasinoSettings) {
+    for (chat, records) in _map.lock().unwrap().iter_mut() {
+        let tries = settings.rules(*chat).daily_spins;
+        records.values_mut().for_each(|x| x.tries_left = tries);
+    }
 }
 
 impl From<SlotResult> for String {
@@ -62,30 +368,163 @@ impl From<SlotResult> for String {
             SlotResult::Grapes => String::from("бабулесы"),
             SlotResult::Lemons => String::from("бабло"),
             SlotResult::Sevens => String::from("деньги"),
+            SlotResult::TwoSevens => String::from("почти деньги"),
+            SlotResult::Pair => String::from("мелочь"),
+            SlotResult::AnySeven => String::from("копейка"),
             SlotResult::Nothing => String::from(""),
         }
     }
 }
 
-impl From<SlotResult> for usize {
-    fn from(val: SlotResult) -> Self {
-        match val {
-            SlotResult::Bars => 1,
-            SlotResult::Grapes => 2,
-            SlotResult::Lemons => 3,
-            SlotResult::Sevens => 5,
-            SlotResult::Nothing => 0,
+impl From<[Reel; 3]> for SlotResult {
+    fn from(reels: [Reel; 3]) -> Self {
+        let sevens = reels.iter().filter(|x| **x == Reel::Seven).count();
+        let [a, b, c] = reels;
+        match reels {
+            [Reel::Bar, Reel::Bar, Reel::Bar] => SlotResult::Bars,
+            [Reel::Grapes, Reel::Grapes, Reel::Grapes] => SlotResult::Grapes,
+            [Reel::Lemon, Reel::Lemon, Reel::Lemon] => SlotResult::Lemons,
+            [Reel::Seven, Reel::Seven, Reel::Seven] => SlotResult::Sevens,
+            _ if sevens == 2 => SlotResult::TwoSevens,
+            _ if a == b || b == c || a == c => SlotResult::Pair,
+            _ if sevens == 1 => SlotResult::AnySeven,
+            _ => SlotResult::Nothing,
         }
     }
 }
+
 impl From<i32> for SlotResult {
     fn from(num: i32) -> Self {
-        match num {
-            1 => SlotResult::Bars,
-            22 => SlotResult::Grapes,
-            43 => SlotResult::Lemons,
-            64 => SlotResult::Sevens,
-            _ => SlotResult::Nothing,
+        decode(num).map_or(SlotResult::Nothing, SlotResult::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REELS: [Reel; 4] = [Reel::Bar, Reel::Grapes, Reel::Lemon, Reel::Seven];
+
+    #[test]
+    fn decodes_every_value() {
+        let mut value = 1;
+        for right in REELS {
+            for middle in REELS {
+                for left in REELS {
+                    assert_eq!(decode(value), Some([left, middle, right]), "value {value}");
+                    value += 1;
+                }
+            }
+        }
+        assert_eq!(decode(0), None);
+        assert_eq!(decode(65), None);
+    }
+
+    #[test]
+    fn triples() {
+        assert_eq!(SlotResult::from(1), SlotResult::Bars);
+        assert_eq!(SlotResult::from(22), SlotResult::Grapes);
+        assert_eq!(SlotResult::from(43), SlotResult::Lemons);
+        assert_eq!(SlotResult::from(64), SlotResult::Sevens);
+    }
+
+    #[test]
+    fn partial_matches() {
+        // seven, seven, bar
+        assert_eq!(SlotResult::from(16), SlotResult::TwoSevens);
+        // bar, lemon, bar
+        assert_eq!(SlotResult::from(9), SlotResult::Pair);
+        // seven, grapes, grapes
+        assert_eq!(SlotResult::from(24), SlotResult::Pair);
+        // seven, bar, grapes
+        assert_eq!(SlotResult::from(20), SlotResult::AnySeven);
+        // grapes, bar, lemon
+        assert_eq!(SlotResult::from(34), SlotResult::Nothing);
+    }
+
+    #[test]
+    fn counts_over_all_values() {
+        let count = |x: SlotResult| (1..=64).filter(|v| SlotResult::from(*v) == x).count();
+        assert_eq!(count(SlotResult::Bars), 1);
+        assert_eq!(count(SlotResult::Grapes), 1);
+        assert_eq!(count(SlotResult::Lemons), 1);
+        assert_eq!(count(SlotResult::Sevens), 1);
+        assert_eq!(count(SlotResult::TwoSevens), 9);
+        assert_eq!(count(SlotResult::Pair), 27);
+        assert_eq!(count(SlotResult::AnySeven), 18);
+        assert_eq!(count(SlotResult::Nothing), 6);
+    }
+
+    #[test]
+    fn payouts() {
+        let payouts = Payouts::default();
+        assert_eq!(SlotResult::from(64).payout(&payouts), 5);
+        assert_eq!(SlotResult::from(16).payout(&payouts), 0);
+        assert_eq!(SlotResult::from(34).payout(&payouts), 0);
+        let payouts = Payouts {
+            two_sevens: 2,
+            ..Payouts::default()
+        };
+        assert_eq!(SlotResult::from(16).payout(&payouts), 2);
+    }
+
+    fn settings(reset_time: &str, timezone: &str) -> CasinoSettings {
+        CasinoSettings {
+            reset_time: reset_time.to_string(),
+            timezone: timezone.to_string(),
+            ..CasinoSettings::default()
         }
     }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn reset_boundary() {
+        // 04:00 in Moscow is 01:00 UTC all year round
+        let x = settings("04:00", "Europe/Moscow");
+        let at = utc("2024-05-10T01:00:00Z");
+        assert_eq!(x.last_reset(at), at);
+        assert_eq!(x.next_reset(at), utc("2024-05-11T01:00:00Z"));
+        let before = at - Duration::seconds(1);
+        assert_eq!(x.last_reset(before), utc("2024-05-09T01:00:00Z"));
+        assert_eq!(x.next_reset(before), at);
+    }
+
+    #[test]
+    fn reset_skipped_by_dst() {
+        // 02:30 does not exist in Berlin on 2024-03-31, clocks jump from 02:00 to 03:00
+        let x = settings("02:30", "Europe/Berlin");
+        let date = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        assert_eq!(x.reset_on(date), utc("2024-03-31T01:30:00Z"));
+        assert_eq!(
+            x.reset_on(date.pred_opt().unwrap()),
+            utc("2024-03-30T01:30:00Z")
+        );
+        assert_eq!(
+            x.reset_on(date.succ_opt().unwrap()),
+            utc("2024-04-01T00:30:00Z")
+        );
+        assert_eq!(
+            x.next_reset(utc("2024-03-30T12:00:00Z")),
+            utc("2024-03-31T01:30:00Z")
+        );
+        assert_eq!(
+            x.last_reset(utc("2024-03-31T01:29:59Z")),
+            utc("2024-03-30T01:30:00Z")
+        );
+    }
+
+    #[test]
+    fn reset_repeated_by_dst() {
+        // 02:30 happens twice in Berlin on 2024-10-27, the reset takes the first one
+        let x = settings("02:30", "Europe/Berlin");
+        let date = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap();
+        assert_eq!(x.reset_on(date), utc("2024-10-27T00:30:00Z"));
+        // the second 02:30 does not reset again
+        let second = utc("2024-10-27T01:30:00Z");
+        assert_eq!(x.last_reset(second), utc("2024-10-27T00:30:00Z"));
+        assert_eq!(x.next_reset(second), utc("2024-10-28T01:30:00Z"));
+    }
 }
@@ -0,0 +1,188 @@
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+const BEGIN: &str = "___BEGIN__";
+const END: &str = "___END__";
+
+const TRIES: usize = 10;
+const MAX_OVERLAP_RATIO: f64 = 0.7;
+const MAX_OVERLAP_TOTAL: usize = 15;
+
+/// Word level markov chain, one sentence per line (like `markovify.NewlineText`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chain {
+    state_size: usize,
+    // states are stored joined with spaces so the model stays a plain json map
+    model: HashMap<String, BTreeMap<String, usize>>,
+    // only used to reject verbatim copies, rebuilt from the messages log on start
+    #[serde(skip)]
+    corpus: String,
+    sentences: usize,
+}
+
+impl Chain {
+    pub fn new(state_size: usize) -> Self {
+        Self {
+            state_size: state_size.max(1),
+            model: HashMap::new(),
+            corpus: String::new(),
+            sentences: 0,
+        }
+    }
+
+    pub fn from_text(text: &str, state_size: usize) -> Self {
+        let mut chain = Self::new(state_size);
+        chain.feed_text(text);
+        chain
+    }
+
+    pub fn feed_text(&mut self, text: &str) {
+        text.lines().for_each(|line| self.feed(line));
+    }
+
+    pub fn feed(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return;
+        }
+        let mut state = vec![BEGIN; self.state_size];
+        for word in words.iter().copied().chain(std::iter::once(END)) {
+            *self
+                .model
+                .entry(state.join(" "))
+                .or_default()
+                .entry(word.to_string())
+                .or_insert(0) += 1;
+            state.remove(0);
+            state.push(word);
+        }
+        self.remember(line);
+        self.sentences += 1;
+    }
+
+    /// Number of sentences learned so far.
+    pub fn len(&self) -> usize {
+        self.sentences
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sentences == 0
+    }
+
+    pub fn remember_text(&mut self, text: &str) {
+        text.lines().for_each(|line| self.remember(line));
+    }
+
+    /// Adds the line to the corpus only, for lines the model already counts.
+    pub fn remember(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return;
+        }
+        self.corpus.push('\n');
+        self.corpus.push_str(&words.join(" "));
+    }
+
+    /// Adds everything `other` has learned to this chain.
+    pub fn merge(&mut self, other: &Chain) {
+        for (state, choices) in &other.model {
+            let entry = self.model.entry(state.clone()).or_default();
+            for (word, weight) in choices {
+                *entry.entry(word.clone()).or_insert(0) += weight;
+            }
+        }
+        self.corpus.push_str(&other.corpus);
+        self.sentences += other.sentences;
+    }
+
+    pub fn make_sentence(&self) -> Option<String> {
+        self.make_sentence_from(vec![BEGIN.to_string(); self.state_size])
+    }
+
+    /// Same as markovify: `strict` only starts from sentences beginning with `start`,
+    /// otherwise any state starting with `start` will do.
+    pub fn make_sentence_with_start(&self, start: &str, strict: bool) -> Option<String> {
+        let words: Vec<&str> = start.split_whitespace().collect();
+        if words.is_empty() || words.len() > self.state_size {
+            return None;
+        }
+        let init_states: Vec<Vec<String>> = if strict {
+            let mut state = vec![BEGIN; self.state_size - words.len()];
+            state.extend(&words);
+            vec![state.into_iter().map(String::from).collect()]
+        } else {
+            let mut states: Vec<Vec<String>> = self
+                .model
+                .keys()
+                .map(|key| key.split(' ').map(String::from).collect::<Vec<_>>())
+                .filter(|state| {
+                    state
+                        .iter()
+                        .map(String::as_str)
+                        .filter(|w| *w != BEGIN)
+                        .take(words.len())
+                        .eq(words.iter().copied())
+                })
+                .collect();
+            states.shuffle(&mut rand::thread_rng());
+            states
+        };
+        init_states
+            .into_iter()
+            .filter(|state| self.model.contains_key(&state.join(" ")))
+            .find_map(|state| self.make_sentence_from(state))
+    }
+
+    /// Tries every word of `text` as a non strict start.
+    pub fn make_sentence_with_any_start(&self, text: &str) -> Option<String> {
+        text.split_whitespace()
+            .find_map(|word| self.make_sentence_with_start(word, false))
+    }
+
+    fn make_sentence_from(&self, init_state: Vec<String>) -> Option<String> {
+        let prefix: Vec<String> = init_state.iter().filter(|w| *w != BEGIN).cloned().collect();
+        (0..TRIES).find_map(|_| {
+            let mut words = prefix.clone();
+            words.extend(self.walk(init_state.clone()));
+            self.test_output(&words).then(|| words.join(" "))
+        })
+    }
+
+    fn walk(&self, mut state: Vec<String>) -> Vec<String> {
+        let mut rng = rand::thread_rng();
+        let mut words = vec![];
+        while let Some(next) = self.model.get(&state.join(" ")).map(|x| pick(x, &mut rng)) {
+            if next == END {
+                break;
+            }
+            words.push(next.to_string());
+            state.remove(0);
+            state.push(next.to_string());
+        }
+        words
+    }
+
+    // rejects sentences that copy too much of the corpus verbatim
+    fn test_output(&self, words: &[String]) -> bool {
+        let overlap_max =
+            ((MAX_OVERLAP_RATIO * words.len() as f64).round() as usize).min(MAX_OVERLAP_TOTAL);
+        let overlap_over = overlap_max + 1;
+        let gram_count = words.len().saturating_sub(overlap_max).max(1);
+        (0..gram_count).all(|i| {
+            let gram = words[i..(i + overlap_over).min(words.len())].join(" ");
+            !self.corpus.contains(&gram)
+        })
+    }
+}
+
+fn pick<'a>(choices: &'a BTreeMap<String, usize>, rng: &mut impl Rng) -> &'a str {
+    let mut x = rng.gen_range(0..choices.values().sum::<usize>());
+    for (word, weight) in choices {
+        if x < *weight {
+            return word;
+        }
+        x -= weight;
+    }
+    END
+}
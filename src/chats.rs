@@ -0,0 +1,52 @@
+use crate::utils::write_to_file;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, error::Error};
+use teloxide::types::ChatId;
+
+/// Chats the bot works in. With `allow_all` every chat but the denied ones.
+/// Chats joined or left by the maintainer win over the config lists.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Chats {
+    #[serde(skip)]
+    pub allow_all: bool,
+    // read from the config on every start, so never saved
+    #[serde(skip)]
+    config_allowed: HashSet<ChatId>,
+    #[serde(skip)]
+    config_denied: HashSet<ChatId>,
+    allowed: HashSet<ChatId>,
+    denied: HashSet<ChatId>,
+}
+
+impl Chats {
+    pub fn set_config(
+        &mut self,
+        allowed: impl IntoIterator<Item = ChatId>,
+        denied: impl IntoIterator<Item = ChatId>,
+    ) {
+        self.config_allowed = allowed.into_iter().collect();
+        self.config_denied = denied.into_iter().collect();
+    }
+    pub fn save(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        write_to_file(s, self)
+    }
+    pub fn is_allowed(&self, chat: ChatId) -> bool {
+        if self.denied.contains(&chat) {
+            false
+        } else if self.allowed.contains(&chat) {
+            true
+        } else if self.allow_all {
+            !self.config_denied.contains(&chat)
+        } else {
+            self.config_allowed.contains(&chat)
+        }
+    }
+    pub fn join(&mut self, chat: ChatId) {
+        self.allowed.insert(chat);
+        self.denied.remove(&chat);
+    }
+    pub fn leave(&mut self, chat: ChatId) {
+        self.allowed.remove(&chat);
+        self.denied.insert(chat);
+    }
+}
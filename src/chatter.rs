@@ -0,0 +1,123 @@
+use crate::utils::{read_from_file, write_to_file};
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, fmt};
+use teloxide::types::ChatId;
+
+/// When the bot answers messages nobody asked it to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplyPolicy {
+    pub probability: f64,
+    /// Seconds between two unsolicited replies.
+    pub cooldown: i64,
+    pub only_mentioned: bool,
+    pub keywords: Vec<String>,
+    /// UTC hours `[from, to)` when the bot keeps quiet.
+    pub quiet_hours: Option<(u32, u32)>,
+}
+
+impl Default for ReplyPolicy {
+    fn default() -> Self {
+        Self {
+            probability: 1.0 / 9.0,
+            cooldown: 0,
+            only_mentioned: false,
+            keywords: vec![],
+            quiet_hours: None,
+        }
+    }
+}
+
+impl ReplyPolicy {
+    fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        match self.quiet_hours {
+            Some((from, to)) if from <= to => (from..to).contains(&now.hour()),
+            Some((from, to)) => now.hour() >= from || now.hour() < to,
+            None => false,
+        }
+    }
+
+    fn has_keyword(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|x| text.contains(&x.to_lowercase()))
+    }
+}
+
+impl fmt::Display for ReplyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "шанс ответа: {:.0}%", self.probability * 100.0)?;
+        writeln!(f, "кулдаун: {} сек", self.cooldown)?;
+        writeln!(
+            f,
+            "только по упоминанию: {}",
+            if self.only_mentioned {
+                "да"
+            } else {
+                "нет"
+            }
+        )?;
+        writeln!(f, "ключевые слова: {}", self.keywords.join(", "))?;
+        match self.quiet_hours {
+            Some((from, to)) => write!(f, "тихие часы (UTC): {from}-{to}"),
+            None => write!(f, "тихие часы: нет"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chatter {
+    policies: HashMap<ChatId, ReplyPolicy>,
+    last_reply: HashMap<ChatId, DateTime<Utc>>,
+}
+
+impl Chatter {
+    pub fn try_new(s: &str) -> Result<Self, Box<dyn Error>> {
+        let policies: HashMap<ChatId, ReplyPolicy> = read_from_file(s)?;
+        Ok(Self {
+            policies,
+            last_reply: HashMap::new(),
+        })
+    }
+    pub fn save(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        write_to_file(s, &self.policies)
+    }
+    pub fn policy(&self, chat: ChatId) -> ReplyPolicy {
+        self.policies.get(&chat).cloned().unwrap_or_default()
+    }
+    pub fn policy_mut(&mut self, chat: ChatId) -> &mut ReplyPolicy {
+        self.policies.entry(chat).or_default()
+    }
+
+    /// Mentions and keywords always get an answer, other messages only by chance.
+    /// Mentions ignore the cooldown since they are not unsolicited.
+    pub fn should_reply(
+        &self,
+        chat: ChatId,
+        text: &str,
+        mentioned: bool,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let policy = self.policy(chat);
+        if policy.is_quiet(now) {
+            return false;
+        }
+        if mentioned {
+            return true;
+        }
+        if let Some(last) = self.last_reply.get(&chat) {
+            if now < *last + chrono::Duration::seconds(policy.cooldown) {
+                return false;
+            }
+        }
+        if policy.has_keyword(text) {
+            return true;
+        }
+        !policy.only_mentioned && rand::random::<f64>() < policy.probability
+    }
+    pub fn replied(&mut self, chat: ChatId, now: DateTime<Utc>) {
+        self.last_reply.insert(chat, now);
+    }
+}
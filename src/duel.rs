@@ -1,26 +1,218 @@
-use crate::{utils::read_from_file, ADuel};
+use crate::{
+    casino::{self, Records},
+    games::{self, Game},
+    mutes,
+    royale::{Lobby, LobbyEvent, Throw},
+    utils::{read_from_file, write_to_file},
+    ADuel, ALedger, Casino,
+};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, error::Error};
+use std::{collections::HashMap, error::Error, fmt};
 use teloxide::{
     prelude::*,
-    types::{CallbackQuery, MessageId, UserId},
+    types::{
+        CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageId, User, UserId,
+    },
 };
 
+const ACCEPT: &str = "duel accept";
+const DECLINE: &str = "duel decline";
+const SHOOT: &str = "duel shoot";
+
+/// Finished duels are taken out of `Duel` and handed back.
 pub enum Shoot {
-    Loser(UserId),
-    Draw,
+    Loser(UserId, OneDuel),
+    Draw(OneDuel),
+    /// A round is over but the duel goes on, with the line to add to the message.
+    Round(String),
     None,
 }
 
+/// What a loser the bot cannot mute gets instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    /// `fallback_points` casino points, or the title when there are none.
+    Points,
+    Title,
+}
+
+/// `[duel]` section of the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuelSettings {
+    /// Seconds both players have to throw their dice.
+    pub timeout: i64,
+    /// Seconds the challenged user has to accept.
+    pub accept_timeout: i64,
+    /// Minutes of mute for a challenger whose duel was declined or ignored.
+    pub forfeit_time: i64,
+    /// Rounds of a duel when `/duel` does not say, best of.
+    pub rounds: u32,
+    /// Reroll drawn rounds instead of counting them as played.
+    pub sudden_death: bool,
+    /// Seconds a battle royale lobby takes players.
+    pub lobby_time: i64,
+    /// Penalty for admins, or for everybody when the bot may not restrict members.
+    pub fallback: Fallback,
+    pub fallback_points: usize,
+    /// Worn until the next win.
+    pub fallback_title: String,
+    /// Seconds before a user may challenge anybody again.
+    pub user_cooldown: i64,
+    /// Seconds before the same two users may duel again.
+    pub pair_cooldown: i64,
+    /// Open duels a user may take part in at once.
+    pub max_open_per_user: usize,
+    pub max_open_per_chat: usize,
+}
+
+impl Default for DuelSettings {
+    fn default() -> Self {
+        Self {
+            timeout: 300,
+            accept_timeout: 120,
+            forfeit_time: 1,
+            rounds: 1,
+            sudden_death: false,
+            lobby_time: 60,
+            fallback: Fallback::Points,
+            fallback_points: 10,
+            fallback_title: String::from("🐔Трус"),
+            user_cooldown: 30,
+            pair_cooldown: 300,
+            max_open_per_user: 2,
+            max_open_per_chat: 5,
+        }
+    }
+}
+
+/// What `/duel` was called with: `/duel [minutes] [bo3|bo5] [sd] [game] [betN]`.
+#[derive(Clone, Copy, Debug)]
+pub struct DuelArgs {
+    pub time: i64,
+    pub rounds: u32,
+    pub sudden_death: bool,
+    pub game: &'static dyn Game,
+    /// Casino points each player puts in, the winner takes both.
+    pub stake: usize,
+}
+
+impl DuelArgs {
+    pub fn parse(args: &str, settings: &DuelSettings) -> Self {
+        let mut x = Self {
+            time: 5,
+            rounds: settings.rounds,
+            sudden_death: settings.sudden_death,
+            game: &games::Dice,
+            stake: 0,
+        };
+        for word in args.split_whitespace() {
+            if let Ok(time) = word.parse() {
+                x.time = time;
+            } else if let Some(rounds) = word.strip_prefix("bo").and_then(|x| x.parse().ok()) {
+                x.rounds = rounds;
+            } else if word == "sd" {
+                x.sudden_death = true;
+            } else if let Some(stake) = word.strip_prefix("bet").and_then(|x| x.parse().ok()) {
+                x.stake = stake;
+            } else if let Some(game) = games::from_word(word) {
+                x.game = game;
+            }
+        }
+        x.time = match x.time {
+            16.. => 15,
+            2..=15 => x.time,
+            i64::MIN..=1 => 2,
+        };
+        // odd so that somebody always gets the majority
+        x.rounds = x.rounds.clamp(1, 9) | 1;
+        x
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct DuelRecord {
+    #[serde(default)]
+    pub user_name: String,
     pub win: usize,
     pub lose: usize,
+    /// Wins in a row when positive, losses in a row when negative.
+    #[serde(default)]
+    pub streak: i64,
+    #[serde(default)]
+    pub best_streak: i64,
+    /// Minutes of mute given to opponents.
+    #[serde(default)]
+    pub mute_dealt: i64,
+    #[serde(default)]
+    pub mute_received: i64,
+    /// Battles royale taken part in, their survivals and deaths also count as wins and losses.
+    #[serde(default)]
+    pub royales: usize,
+    /// Shame title of a loser who could not be muted.
+    #[serde(default)]
+    pub title: Option<String>,
 }
 
 impl DuelRecord {
-    fn new() -> Self {
-        Self { win: 0, lose: 0 }
+    fn new(name: String) -> Self {
+        Self {
+            user_name: name,
+            win: 0,
+            lose: 0,
+            streak: 0,
+            best_streak: 0,
+            mute_dealt: 0,
+            mute_received: 0,
+            royales: 0,
+            title: None,
+        }
+    }
+    fn won(&mut self, time: i64) {
+        self.win += 1;
+        self.streak = self.streak.max(0) + 1;
+        self.best_streak = self.best_streak.max(self.streak);
+        self.mute_dealt += time;
+        self.title = None;
+    }
+    fn lost(&mut self, time: i64) {
+        self.lose += 1;
+        self.streak = self.streak.min(0) - 1;
+        self.mute_received += time;
+    }
+    pub fn win_rate(&self) -> f64 {
+        if self.win + self.lose == 0 {
+            0.0
+        } else {
+            self.win as f64 / (self.win + self.lose) as f64
+        }
+    }
+}
+
+impl fmt::Display for DuelRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "⚔️Дуэли {}⚔️", self.user_name)?;
+        if let Some(title) = &self.title {
+            writeln!(f, "титул: {title}")?;
+        }
+        writeln!(
+            f,
+            "побед {}, поражений {}, {:.0}% побед",
+            self.win,
+            self.lose,
+            self.win_rate() * 100.0
+        )?;
+        match self.streak {
+            0 => writeln!(f, "серии нет")?,
+            1.. => writeln!(f, "побед подряд: {}🔥", self.streak)?,
+            _ => writeln!(f, "поражений подряд: {}", -self.streak)?,
+        }
+        writeln!(f, "лучшая серия: {}🏆", self.best_streak)?;
+        writeln!(f, "королевских битв: {}👑", self.royales)?;
+        writeln!(f, "отправил в мут на {} минут🙊", self.mute_dealt)?;
+        write!(f, "просидел в муте {} минут☠️", self.mute_received)
     }
 }
 
@@ -38,38 +230,153 @@ impl PartialOrd for DuelRecord {
 #[derive(Debug)]
 pub struct OneDuel {
     pub time: i64,
+    pub chat: ChatId,
     pub msg: MessageId,
+    /// Dice thrown in this duel, deleted once it is over.
+    pub dices: Vec<MessageId>,
+    pub created: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+    /// Nobody throws until dantes accepts the challenge.
+    pub accepted: bool,
+    pub rounds: u32,
+    pub sudden_death: bool,
+    pub played: u32,
+    /// Rounds won by pushkin and dantes.
+    pub score: (u32, u32),
+    pub game: &'static dyn Game,
+    pub stake: usize,
+    /// Players the bot cannot mute, they pay the fallback penalty.
+    pub unmutable: Vec<UserId>,
 
     pub pushkin_name: String,
+    pub pushkin_mention: String,
     pub pushkin: UserId,
     pub pushkin_throw: Option<i32>,
 
     pub dantes_name: String,
+    pub dantes_mention: String,
     pub dantes: UserId,
     pub dantes_throw: Option<i32>,
 }
+
+pub(crate) fn mention(user: &User) -> String {
+    user.mention().unwrap_or_else(|| user.full_name())
+}
+
+pub(crate) fn game_line(game: &dyn Game) -> String {
+    match game.name() {
+        "" => String::new(),
+        name => format!("{name}\n"),
+    }
+}
+
+fn stake_line(stake: usize) -> String {
+    match stake {
+        0 => String::new(),
+        x => format!("💰На кону {x} очков казино\n"),
+    }
+}
+
+pub fn challenge_text(
+    pushkin: &User,
+    dantes: &User,
+    args: DuelArgs,
+    unmutable: &[UserId],
+) -> String {
+    let shields: String = [pushkin, dantes]
+        .into_iter()
+        .filter(|x| unmutable.contains(&x.id))
+        .map(|x| {
+            format!(
+                "🛡{} не может быть замучен, вместо мута штраф\n",
+                x.full_name()
+            )
+        })
+        .collect();
+    format!(
+        "⚔️{} вызывает {} на дуэль⚔️\n{}⏱Ставка - {} минут мута🙊\n{}{shields}Принимаешь вызов?\n",
+        mention(pushkin),
+        mention(dantes),
+        game_line(args.game),
+        args.time,
+        stake_line(args.stake)
+    )
+}
+
+pub fn challenge_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("принять", ACCEPT),
+        InlineKeyboardButton::callback("отказаться", DECLINE),
+    ]])
+}
+
+fn shoot_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("шут", SHOOT)]])
+}
 impl OneDuel {
     pub fn new(
-        pushkin: UserId,
-        dantes: UserId,
-        pushkin_name: String,
-        dantes_name: String,
+        chat: ChatId,
         x: MessageId,
-        t: i64,
+        pushkin: &User,
+        dantes: &User,
+        args: DuelArgs,
+        unmutable: Vec<UserId>,
+        accept_timeout: Duration,
     ) -> Self {
+        let created = Utc::now();
         Self {
-            time: t,
+            time: args.time,
+            chat,
             msg: x,
-            pushkin_name,
-            pushkin,
+            dices: vec![],
+            created,
+            deadline: created + accept_timeout,
+            accepted: false,
+            rounds: args.rounds,
+            sudden_death: args.sudden_death,
+            played: 0,
+            score: (0, 0),
+            game: args.game,
+            stake: args.stake,
+            unmutable,
+            pushkin_name: pushkin.full_name(),
+            pushkin_mention: mention(pushkin),
+            pushkin: pushkin.id,
             pushkin_throw: None,
-            dantes_name,
-            dantes,
+            dantes_name: dantes.full_name(),
+            dantes_mention: mention(dantes),
+            dantes: dantes.id,
             dantes_throw: None,
         }
     }
+    /// Message text once the challenge is accepted.
+    pub fn text(&self) -> String {
+        let mut text = format!(
+            "⚔️Дуэль между {} и {}⚔️\n{}⏱Ставка - {} минут мута🙊\n{}",
+            self.pushkin_mention,
+            self.dantes_mention,
+            game_line(self.game),
+            self.time,
+            stake_line(self.stake)
+        );
+        if self.rounds > 1 {
+            text += &format!("🔁До {} побед\n", self.rounds / 2 + 1);
+        }
+        if self.sudden_death {
+            text += "💀Ничьи перебрасываются\n";
+        }
+        text + "Бросайте кубики🎲\n"
+    }
+    pub fn score_line(&self) -> String {
+        format!(
+            "Раунд {}: {} {}:{} {}\n",
+            self.played, self.pushkin_name, self.score.0, self.score.1, self.dantes_name
+        )
+    }
     fn cannot_shoot(&self, x: UserId) -> bool {
-        if x == self.pushkin && self.pushkin_throw.is_none() {
+        if !self.accepted {
+            true
+        } else if x == self.pushkin && self.pushkin_throw.is_none() {
             false
         } else if x == self.dantes && self.dantes_throw.is_none() {
             false
@@ -88,6 +395,13 @@ impl OneDuel {
             self.dantes_throw = Some(value);
         }
     }
+    fn opponent(&self, id: UserId) -> UserId {
+        if id == self.pushkin {
+            self.dantes
+        } else {
+            self.pushkin
+        }
+    }
     fn opponent_name(&self, id: UserId) -> (String, String) {
         if id == self.pushkin {
             (self.dantes_name.clone(), self.pushkin_name.clone())
@@ -98,58 +412,566 @@ impl OneDuel {
     fn results(&self) -> (i32, i32) {
         (self.pushkin_throw.unwrap(), self.dantes_throw.unwrap())
     }
+    /// Gives back the stakes held since the challenge was accepted.
+    fn refund(&self, casino: &mut Records) {
+        if self.accepted {
+            casino::pay(casino, self.chat, self.pushkin, self.stake);
+            casino::pay(casino, self.chat, self.dantes, self.stake);
+        }
+    }
+}
+
+// message ids are only unique inside one chat
+type DuelKey = (ChatId, MessageId);
+
+// the same key whoever challenged whom
+fn pair(chat: ChatId, a: UserId, b: UserId) -> (ChatId, UserId, UserId) {
+    (chat, a.min(b), a.max(b))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DuelFile {
+    PerChat(HashMap<ChatId, HashMap<UserId, DuelRecord>>),
+    Legacy(HashMap<UserId, DuelRecord>),
 }
 
 #[derive(Debug)]
 pub struct Duel {
-    records: HashMap<UserId, DuelRecord>,
-    open_duels: HashMap<MessageId, OneDuel>,
-    dices: Vec<MessageId>,
+    records: HashMap<ChatId, HashMap<UserId, DuelRecord>>,
+    open_duels: HashMap<DuelKey, OneDuel>,
+    lobbies: HashMap<DuelKey, Lobby>,
+    // when a user last challenged somebody, and when two users last met
+    last_challenge: HashMap<(ChatId, UserId), DateTime<Utc>>,
+    last_pair: HashMap<(ChatId, UserId, UserId), DateTime<Utc>>,
+    settings: DuelSettings,
 }
 
 impl Duel {
-    pub fn try_new(s: &str) -> Result<Self, Box<dyn Error>> {
-        let records: HashMap<UserId, DuelRecord> = read_from_file(s)?;
+    /// Records saved before duels were per chat are moved to `legacy_chat`.
+    pub fn try_new(
+        s: &str,
+        legacy_chat: ChatId,
+        settings: DuelSettings,
+    ) -> Result<Self, Box<dyn Error>> {
+        let records = match read_from_file(s)? {
+            DuelFile::PerChat(x) => x,
+            DuelFile::Legacy(x) => HashMap::from([(legacy_chat, x)]),
+        };
         Ok(Self {
             records,
             open_duels: HashMap::new(),
-            dices: vec![],
+            lobbies: HashMap::new(),
+            last_challenge: HashMap::new(),
+            last_pair: HashMap::new(),
+            settings,
         })
     }
-    pub fn save(&self, s: &str) {
-        crate::to_writer(std::fs::File::create(s).unwrap(), &self.records).unwrap();
+    pub fn save(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        write_to_file(s, &self.records)
+    }
+    pub fn records(&self, chat: ChatId) -> Vec<(UserId, DuelRecord)> {
+        self.records
+            .get(&chat)
+            .map(|x| x.iter().map(|(id, x)| (*id, x.clone())).collect())
+            .unwrap_or_default()
+    }
+    pub fn record(&self, chat: ChatId, user: UserId) -> Option<DuelRecord> {
+        self.records.get(&chat)?.get(&user).cloned()
+    }
+    /// Wins and losses of every user summed over all chats.
+    pub fn global_records(&self) -> Vec<(UserId, DuelRecord)> {
+        let mut users: HashMap<UserId, DuelRecord> = HashMap::new();
+        for (id, x) in self.records.values().flatten() {
+            let y = users
+                .entry(*id)
+                .or_insert(DuelRecord::new(x.user_name.clone()));
+            y.win += x.win;
+            y.lose += x.lose;
+            y.best_streak = y.best_streak.max(x.best_streak);
+            y.mute_dealt += x.mute_dealt;
+            y.mute_received += x.mute_received;
+            y.royales += x.royales;
+        }
+        users.into_iter().collect()
+    }
+    pub fn parse_args(&self, args: &str) -> DuelArgs {
+        DuelArgs::parse(args, &self.settings)
+    }
+
+    /// Refuses challenges coming too often or while too many duels are open,
+    /// with the reason to tell the challenger. Battles royale have no `dantes`
+    /// and count as open duels of their players.
+    pub fn check_challenge(
+        &self,
+        chat: ChatId,
+        pushkin: UserId,
+        dantes: Option<UserId>,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let wait = |last: Option<&DateTime<Utc>>, cooldown: i64| {
+            last.map(|x| (*x + Duration::seconds(cooldown) - now).num_seconds())
+                .filter(|x| *x > 0)
+        };
+        if let Some(x) = wait(
+            self.last_challenge.get(&(chat, pushkin)),
+            self.settings.user_cooldown,
+        ) {
+            return Err(format!(
+                "⏳Подожди {x} сек, прежде чем снова вызывать на дуэль"
+            ));
+        }
+        if let Some(x) = dantes.and_then(|dantes| {
+            wait(
+                self.last_pair.get(&pair(chat, pushkin, dantes)),
+                self.settings.pair_cooldown,
+            )
+        }) {
+            return Err(format!("⏳Вы недавно уже стрелялись, реванш через {x} сек"));
+        }
+        let open = self.open_duels.values().filter(|x| x.chat == chat).count()
+            + self.lobbies.values().filter(|x| x.chat == chat).count();
+        if open >= self.settings.max_open_per_chat {
+            return Err(format!("🚫В чате уже идёт {open} дуэлей, дождитесь конца"));
+        }
+        for user in [Some(pushkin), dantes].into_iter().flatten() {
+            let n = self.open_count(chat, user);
+            if n >= self.settings.max_open_per_user {
+                return Err(format!(
+                    "🚫{} уже участвует в {n} дуэлях, сначала закончите их",
+                    if user == pushkin {
+                        "Ты"
+                    } else {
+                        "Соперник"
+                    }
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Duels and battles royale `user` takes part in.
+    fn open_count(&self, chat: ChatId, user: UserId) -> usize {
+        self.open_duels
+            .values()
+            .filter(|x| x.chat == chat && (x.pushkin == user || x.dantes == user))
+            .count()
+            + self
+                .lobbies
+                .values()
+                .filter(|x| x.chat == chat && x.players.iter().any(|x| x.id == user))
+                .count()
     }
+
+    /// Opens a challenge, the duel itself starts once it is accepted.
     pub fn start_duel(
         &mut self,
-        pushkin: UserId,
-        dantes: UserId,
-        user1: String,
-        user2: String,
+        chat: ChatId,
         x: MessageId,
-        t: i64,
+        pushkin: &User,
+        dantes: &User,
+        args: DuelArgs,
+        unmutable: Vec<UserId>,
     ) {
-        self.records.entry(pushkin).or_insert(DuelRecord::new());
-        self.records.entry(dantes).or_insert(DuelRecord::new());
+        let records = self.records.entry(chat).or_default();
+        for user in [pushkin, dantes] {
+            records
+                .entry(user.id)
+                .or_insert(DuelRecord::new(user.full_name()))
+                .user_name = user.full_name();
+        }
 
-        self.open_duels
-            .insert(x, OneDuel::new(pushkin, dantes, user1, user2, x, t));
+        let now = Utc::now();
+        self.last_challenge.insert((chat, pushkin.id), now);
+        self.last_pair
+            .insert(pair(chat, pushkin.id, dantes.id), now);
+
+        let accept_timeout = Duration::seconds(self.settings.accept_timeout);
+        self.open_duels.insert(
+            (chat, x),
+            OneDuel::new(chat, x, pushkin, dantes, args, unmutable, accept_timeout),
+        );
     }
 
-    pub fn shoot(&mut self, x: MessageId, id: UserId, value: i32) -> Shoot {
-        match self.open_duels.get_mut(&x) {
-            Some(y) => y.set_value(id, value),
-            None => return Shoot::None,
+    /// Returns the duel text if `user` was the one challenged. Both stakes are held
+    /// from now on, so nobody can spend or give them away before the duel ends.
+    pub fn accept(
+        &mut self,
+        casino: &mut Records,
+        chat: ChatId,
+        x: MessageId,
+        user: UserId,
+    ) -> Option<String> {
+        let timeout = Duration::seconds(self.settings.timeout);
+        let y = self
+            .open_duels
+            .get_mut(&(chat, x))
+            .filter(|y| !y.accepted && y.dantes == user)?;
+        y.accepted = true;
+        y.deadline = Utc::now() + timeout;
+        casino::fine(casino, chat, y.pushkin, y.stake);
+        casino::fine(casino, chat, y.dantes, y.stake);
+        Some(y.text())
+    }
+
+    /// Whether both players of the duel still have the points it is played for.
+    pub fn covered(&self, chat: ChatId, x: MessageId, casino: &Records) -> bool {
+        match self.open_duels.get(&(chat, x)) {
+            Some(y) => {
+                casino::can_cover(casino, chat, y.pushkin, y.stake)
+                    && casino::can_cover(casino, chat, y.dantes, y.stake)
+            }
+            None => true,
+        }
+    }
+
+    /// Takes out the challenge if `user` was the one challenged.
+    pub fn decline(&mut self, chat: ChatId, x: MessageId, user: UserId) -> Option<OneDuel> {
+        let challenged = matches!(
+            self.open_duels.get(&(chat, x)),
+            Some(y) if !y.accepted && y.dantes == user
+        );
+        if challenged {
+            self.open_duels.remove(&(chat, x))
+        } else {
+            None
+        }
+    }
+
+    pub fn add_dice(&mut self, chat: ChatId, x: MessageId, dice: MessageId) {
+        if let Some(y) = self.open_duels.get_mut(&(chat, x)) {
+            y.dices.push(dice);
+        }
+    }
+
+    /// Gives back the stakes of the duels still open, they do not survive a restart.
+    pub fn refund_open(&mut self, casino: &mut Records) {
+        for (_, x) in self.open_duels.drain() {
+            x.refund(casino);
         }
+    }
+
+    /// Forgets the challenges whose cooldowns are over.
+    fn forget_cooldowns(&mut self, now: DateTime<Utc>) {
+        let user = Duration::seconds(self.settings.user_cooldown);
+        let pair = Duration::seconds(self.settings.pair_cooldown);
+        self.last_challenge.retain(|_, x| *x + user > now);
+        self.last_pair.retain(|_, x| *x + pair > now);
+    }
 
-        if self.open_duels[&x].not_ready() {
+    /// Takes out the duels somebody did not throw in time.
+    pub fn take_expired(&mut self, now: DateTime<Utc>) -> Vec<(ChatId, OneDuel)> {
+        let expired: Vec<DuelKey> = self
+            .open_duels
+            .iter()
+            .filter(|(_, x)| x.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.open_duels.remove(&key).map(|x| (key.0, x)))
+            .collect()
+    }
+
+    pub fn shoot(&mut self, chat: ChatId, x: MessageId, id: UserId, value: i32) -> Shoot {
+        let x = (chat, x);
+        let timeout = Duration::seconds(self.settings.timeout);
+        let y = match self.open_duels.get_mut(&x) {
+            Some(y) => y,
+            None => return Shoot::None,
+        };
+        y.set_value(id, value);
+        if y.not_ready() {
             return Shoot::None;
         }
 
-        let (pushkin, dantes) = self.open_duels[&x].results();
-        match pushkin.cmp(&dantes) {
-            std::cmp::Ordering::Less => Shoot::Loser(self.open_duels[&x].pushkin),
-            std::cmp::Ordering::Equal => Shoot::Draw,
-            std::cmp::Ordering::Greater => Shoot::Loser(self.open_duels[&x].dantes),
+        let (pushkin, dantes) = y.results();
+        y.pushkin_throw = None;
+        y.dantes_throw = None;
+        y.deadline = Utc::now() + timeout;
+        match y.game.compare(pushkin, dantes) {
+            std::cmp::Ordering::Less => y.score.1 += 1,
+            std::cmp::Ordering::Equal if y.sudden_death => {
+                return Shoot::Round("Ничья, перебрасываем🎲\n".to_string())
+            }
+            std::cmp::Ordering::Equal => (),
+            std::cmp::Ordering::Greater => y.score.0 += 1,
+        }
+        y.played += 1;
+        let need = y.rounds / 2 + 1;
+        let loser = if y.score.0 >= need {
+            y.dantes
+        } else if y.score.1 >= need {
+            y.pushkin
+        } else if y.played < y.rounds {
+            return Shoot::Round(y.score_line());
+        } else {
+            match y.score.0.cmp(&y.score.1) {
+                std::cmp::Ordering::Less => y.pushkin,
+                std::cmp::Ordering::Equal => {
+                    return Shoot::Draw(self.open_duels.remove(&x).unwrap())
+                }
+                std::cmp::Ordering::Greater => y.dantes,
+            }
+        };
+
+        let one_duel = self.open_duels.remove(&x).unwrap();
+        let winner = one_duel.opponent(loser);
+        // a fined loser sits no mute, so none is counted
+        let time = if one_duel.unmutable.contains(&loser) {
+            0
+        } else {
+            one_duel.time
+        };
+        let records = self.records.entry(chat).or_default();
+        if let Some(y) = records.get_mut(&winner) {
+            y.won(time);
+        }
+        if let Some(y) = records.get_mut(&loser) {
+            y.lost(time);
+        }
+        Shoot::Loser(loser, one_duel)
+    }
+
+    /// Penalty for a loser the bot cannot mute, returns the line telling about it.
+    pub fn fine(&mut self, casino: &mut Records, chat: ChatId, user: UserId) -> String {
+        if self.settings.fallback == Fallback::Points {
+            let name = self
+                .record(chat, user)
+                .map(|x| x.user_name)
+                .unwrap_or_default();
+            let fined = casino::fine(casino, chat, user, self.settings.fallback_points);
+            if fined > 0 {
+                return format!("💸{name} теряет {fined} очков казино\n");
+            }
+        }
+        self.give_title(chat, user)
+    }
+
+    pub fn give_title(&mut self, chat: ChatId, user: UserId) -> String {
+        let title = self.settings.fallback_title.clone();
+        match self.records.get_mut(&chat).and_then(|x| x.get_mut(&user)) {
+            Some(x) => {
+                let text = format!("🏷{} получает титул «{title}»\n", x.user_name);
+                x.title = Some(title);
+                text
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Returns the lobby text with the host as the first player.
+    pub fn open_lobby(
+        &mut self,
+        chat: ChatId,
+        x: MessageId,
+        host: &User,
+        unmutable: bool,
+        args: DuelArgs,
+    ) -> String {
+        self.last_challenge.insert((chat, host.id), Utc::now());
+        let join_time = Duration::seconds(self.settings.lobby_time);
+        let lobby = Lobby::new(chat, x, host, unmutable, args, join_time);
+        let text = lobby.text();
+        self.lobbies.insert((chat, x), lobby);
+        text
+    }
+
+    /// Returns the new lobby text if `user` was let in, or why not when they already
+    /// take part in too many duels.
+    pub fn join_lobby(
+        &mut self,
+        chat: ChatId,
+        x: MessageId,
+        user: &User,
+        unmutable: bool,
+    ) -> Option<Result<String, String>> {
+        let n = self.open_count(chat, user.id);
+        let max = self.settings.max_open_per_user;
+        let y = self.lobbies.get_mut(&(chat, x))?;
+        // pressing the button again is not joining one more battle
+        if !y.started && !y.players.iter().any(|x| x.id == user.id) && n >= max {
+            return Some(Err(format!(
+                "🚫{} уже участвует в {n} дуэлях, сначала закончите их",
+                mention(user)
+            )));
+        }
+        y.join(user, unmutable).then(|| Ok(y.text()))
+    }
+
+    /// The game `user` is to throw in, if it is their turn.
+    pub fn lobby_game(
+        &self,
+        chat: ChatId,
+        x: MessageId,
+        user: UserId,
+    ) -> Option<&'static dyn Game> {
+        self.lobbies
+            .get(&(chat, x))
+            .filter(|y| y.can_throw(user))
+            .map(|y| y.game)
+    }
+
+    pub fn lobby_throw(
+        &mut self,
+        chat: ChatId,
+        x: MessageId,
+        user: UserId,
+        dice: MessageId,
+        value: i32,
+    ) -> Throw {
+        let y = match self.lobbies.get_mut(&(chat, x)) {
+            Some(y) => y,
+            None => return Throw::None,
+        };
+        y.dices.push(dice);
+        if !y.can_throw(user) {
+            return Throw::None;
+        }
+        y.set_value(user, value);
+        if !y.done() {
+            return Throw::Thrown(y.text());
+        }
+        let lobby = self.lobbies.remove(&(chat, x)).unwrap();
+        let losers = self.finish_lobby(&lobby);
+        Throw::Finished(lobby, losers)
+    }
+
+    /// Closes the lobbies whose deadline has passed: starts full ones, cancels the
+    /// rest and ends the battles somebody did not throw in.
+    pub fn take_lobbies(&mut self, now: DateTime<Utc>) -> Vec<LobbyEvent> {
+        let timeout = Duration::seconds(self.settings.timeout);
+        let expired: Vec<DuelKey> = self
+            .lobbies
+            .iter()
+            .filter(|(_, x)| x.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        let mut events = vec![];
+        for key in expired {
+            let y = self.lobbies.get_mut(&key).unwrap();
+            if !y.started && y.players.len() >= 2 {
+                y.started = true;
+                y.deadline = now + timeout;
+                events.push(LobbyEvent::Started(key.0, key.1, y.text()));
+                continue;
+            }
+            let lobby = self.lobbies.remove(&key).unwrap();
+            if lobby.started {
+                let losers = self.finish_lobby(&lobby);
+                events.push(LobbyEvent::Finished(lobby, losers));
+            } else {
+                events.push(LobbyEvent::Cancelled(lobby));
+            }
+        }
+        events
+    }
+
+    fn finish_lobby(&mut self, lobby: &Lobby) -> Vec<UserId> {
+        let losers = lobby.losers();
+        let records = self.records.entry(lobby.chat).or_default();
+        for x in &lobby.players {
+            let y = records
+                .entry(x.id)
+                .or_insert(DuelRecord::new(x.name.clone()));
+            y.user_name = x.name.clone();
+            y.royales += 1;
+            if losers.contains(&x.id) {
+                y.lost(if x.unmutable { 0 } else { lobby.time });
+            } else if !losers.is_empty() {
+                y.won(0);
+            }
+        }
+        losers
+    }
+}
+
+async fn delete_dices(bot: &Bot, one_duel: &OneDuel) {
+    for x in &one_duel.dices {
+        if let Err(e) = bot.delete_message(one_duel.chat, *x).await {
+            log::warn!("failed to delete duel dice: {e}");
+        }
+    }
+}
+
+/// The challenger pays for a declined or ignored challenge.
+async fn forfeit(
+    bot: &Bot,
+    duel: &ADuel,
+    ledger: &ALedger,
+    one_duel: &OneDuel,
+    time: i64,
+    why: &str,
+) {
+    let mut text = format!(
+        "⚔️{} вызывает {} на дуэль⚔️\n{why}\n",
+        one_duel.pushkin_mention, one_duel.dantes_mention
+    );
+    if time > 0 && one_duel.unmutable.contains(&one_duel.pushkin) {
+        text += &duel
+            .lock()
+            .unwrap()
+            .give_title(one_duel.chat, one_duel.pushkin);
+    } else if time > 0 {
+        text += &format!(
+            "{} отправляется в бан на {time} минут☠️\n",
+            one_duel.pushkin_name
+        );
+        if let Err(e) = mutes::mute(
+            bot,
+            ledger,
+            one_duel.chat,
+            one_duel.pushkin,
+            &one_duel.pushkin_name,
+            time,
+            why,
+        )
+        .await
+        {
+            log::warn!("failed to mute duel challenger: {e}");
+        }
+    }
+    if let Err(e) = bot
+        .edit_message_text(one_duel.chat, one_duel.msg, text)
+        .await
+    {
+        log::warn!("failed to edit duel: {e}");
+    }
+}
+
+/// Cancels the duels whose deadline has passed and gives their stakes back.
+pub async fn sweep(bot: &Bot, casino: &Casino, duel: &ADuel, ledger: &ALedger) {
+    let (expired, forfeit_time) = {
+        let mut casino = casino.lock().unwrap();
+        let mut duel = duel.lock().unwrap();
+        let now = Utc::now();
+        duel.forget_cooldowns(now);
+        let expired = duel.take_expired(now);
+        for (_, x) in &expired {
+            x.refund(&mut casino);
+        }
+        (expired, duel.settings.forfeit_time)
+    };
+    for (chat, x) in expired {
+        if !x.accepted {
+            forfeit(
+                bot,
+                duel,
+                ledger,
+                &x,
+                forfeit_time,
+                "⌛Вызов проигнорирован",
+            )
+            .await;
+            continue;
+        }
+        delete_dices(bot, &x).await;
+        let text = format!(
+            "⚔️Дуэль между {} и {}⚔️\n⌛Время вышло, дуэль отменена\n",
+            x.pushkin_name, x.dantes_name
+        );
+        if let Err(e) = bot.edit_message_text(chat, x.msg, text).await {
+            log::warn!("failed to expire duel: {e}");
         }
     }
 }
@@ -157,48 +979,132 @@ impl Duel {
 pub async fn callback_handler(
     bot: Bot,
     q: CallbackQuery,
+    casino: Casino,
     duel: ADuel,
+    ledger: ALedger,
 ) -> Result<(), teloxide::RequestError> {
     bot.answer_callback_query(&q.id).await?;
+    let (chat_id, msg_id) = match &q.message {
+        Some(x) => (x.chat.id, x.id),
+        None => return Ok(()),
+    };
+    match q.data.as_deref() {
+        Some(ACCEPT) => {
+            let accepted = {
+                let mut casino = casino.lock().unwrap();
+                let mut duel = duel.lock().unwrap();
+                if duel.covered(chat_id, msg_id, &casino) {
+                    duel.accept(&mut casino, chat_id, msg_id, q.from.id).map(Ok)
+                } else {
+                    duel.decline(chat_id, msg_id, q.from.id).map(Err)
+                }
+            };
+            match accepted {
+                Some(Ok(text)) => {
+                    bot.edit_message_text(chat_id, msg_id, text)
+                        .reply_markup(shoot_keyboard())
+                        .await?;
+                }
+                Some(Err(x)) => {
+                    let text = format!(
+                        "⚔️Дуэль между {} и {}⚔️\n💸Не хватает очков на ставку, дуэль отменена\n",
+                        x.pushkin_name, x.dantes_name
+                    );
+                    bot.edit_message_text(chat_id, msg_id, text).await?;
+                }
+                None => (),
+            }
+            return Ok(());
+        }
+        Some(DECLINE) => {
+            let (one_duel, forfeit_time) = {
+                let mut duel = duel.lock().unwrap();
+                (
+                    duel.decline(chat_id, msg_id, q.from.id),
+                    duel.settings.forfeit_time,
+                )
+            };
+            if let Some(one_duel) = one_duel {
+                forfeit(
+                    &bot,
+                    &duel,
+                    &ledger,
+                    &one_duel,
+                    forfeit_time,
+                    "🏳️Вызов отклонён",
+                )
+                .await;
+            }
+            return Ok(());
+        }
+        _ => (),
+    }
     let is_restricted = bot
         .get_chat_member(q.message.as_ref().unwrap().chat.id, q.from.id)
         .await?
         .is_restricted();
-    if let Some(mm) = duel
-        .lock()
-        .unwrap()
-        .open_duels
-        .get(&q.message.as_ref().unwrap().id)
-    {
-        if mm.cannot_shoot(q.from.id) || dbg!(is_restricted) {
-            dbg!(&mm);
+    let game = if let Some(mm) = duel.lock().unwrap().open_duels.get(&(
+        q.message.as_ref().unwrap().chat.id,
+        q.message.as_ref().unwrap().id,
+    )) {
+        if mm.cannot_shoot(q.from.id) || is_restricted {
             return Ok(());
         }
-    }
+        mm.game
+    } else {
+        // finished or expired duel
+        return Ok(());
+    };
     if let Some(_) = q.data {
         bot.answer_callback_query(q.id).await?;
 
         let tmpd = bot
             .send_dice(q.message.as_ref().unwrap().chat.id.clone())
-            .emoji(teloxide::types::DiceEmoji::Dice)
+            .emoji(game.emoji())
             .await?;
-        duel.lock().unwrap().dices.push(tmpd.id);
+        let tmp = q.message.as_ref().unwrap();
+        duel.lock().unwrap().add_dice(tmp.chat.id, tmp.id, tmpd.id);
 
         let value = match tmpd.kind {
             teloxide::types::MessageKind::Dice(x) => x.dice.value,
             _ => 0,
         };
-        let tmp = q.message.as_ref().unwrap();
         let text = format!(
-            "{}\n{} выбрасывает {value} очков\n",
+            "{}\n{} {}\n",
             tmp.text().unwrap(),
-            q.from.mention().unwrap()
+            mention(&q.from),
+            game.describe(value)
         );
         let user_id = q.from.id;
-        let loser = duel.lock().unwrap().shoot(tmp.id, user_id, value);
-
-        let cb = teloxide::types::InlineKeyboardButton::callback("шут", "шут");
-        let kbd = teloxide::types::InlineKeyboardMarkup::new(vec![vec![cb]]);
+        // points change hands under the same locks that end the duel
+        let (loser, paid, fine) = {
+            let mut casino = casino.lock().unwrap();
+            let mut duel = duel.lock().unwrap();
+            let loser = duel.shoot(tmp.chat.id, tmp.id, user_id, value);
+            let paid = match &loser {
+                Shoot::Loser(loser, one_duel) => {
+                    let winner = one_duel.opponent(*loser);
+                    casino::pay(&mut casino, tmp.chat.id, winner, 2 * one_duel.stake);
+                    one_duel.stake
+                }
+                Shoot::Draw(one_duel) => {
+                    one_duel.refund(&mut casino);
+                    0
+                }
+                _ => 0,
+            };
+            let fine = match &loser {
+                Shoot::Loser(loser, one_duel) if one_duel.unmutable.contains(loser) => {
+                    Some(duel.fine(&mut casino, tmp.chat.id, *loser))
+                }
+                _ => None,
+            };
+            (loser, paid, fine)
+        };
+        let text = match &loser {
+            Shoot::Round(line) => text + line,
+            _ => text,
+        };
 
         tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
         if let Some(teloxide::types::Message {
@@ -206,41 +1112,52 @@ pub async fn callback_handler(
         }) = q.message
         {
             bot.edit_message_text(chat.id, *id, &text)
-                .reply_markup(kbd)
+                .reply_markup(shoot_keyboard())
                 .await?;
         }
         match loser {
-            Shoot::Loser(loser) => {
-                let mut to_delete = vec![];
-                std::mem::swap(&mut to_delete, &mut duel.lock().unwrap().dices);
-
-                for x in to_delete {
-                    bot.delete_message(tmp.chat.id, x).await?;
-                }
-                let (winner_name, loser_name) =
-                    duel.lock().unwrap().open_duels[&tmp.id].opponent_name(loser);
-                let text = format!(
-                    "{}Побеждает {winner_name}🏆\n{loser_name} отправляется в бан☠️\n",
-                    text
-                );
+            Shoot::Loser(loser, one_duel) => {
+                delete_dices(&bot, &one_duel).await;
+                let (winner_name, loser_name) = one_duel.opponent_name(loser);
+                let score = if one_duel.rounds > 1 {
+                    one_duel.score_line()
+                } else {
+                    String::new()
+                };
+                let paid = match paid {
+                    0 => String::new(),
+                    x => format!("💰{winner_name} забирает {x} очков\n"),
+                };
+                let penalty = match &fine {
+                    Some(x) => x.clone(),
+                    None => format!("{loser_name} отправляется в бан☠️\n"),
+                };
+                let text = format!("{}{score}Побеждает {winner_name}🏆\n{penalty}{paid}", text);
                 bot.edit_message_text(tmp.chat.id, tmp.id, text).await?;
-                let time = duel.lock().unwrap().open_duels[&tmp.id].time;
-                bot.restrict_chat_member(
+                if fine.is_some() {
+                    return Ok(());
+                }
+                let time = one_duel.time;
+                mutes::mute(
+                    &bot,
+                    &ledger,
                     tmp.chat.id,
                     loser,
-                    teloxide::types::ChatPermissions::empty(),
+                    &loser_name,
+                    time,
+                    "⚔️Проиграл дуэль",
                 )
-                .until_date(tmpd.date + chrono::Duration::minutes(time))
                 .await?;
             }
-            Shoot::Draw => {
+            Shoot::Draw(one_duel) => {
+                delete_dices(&bot, &one_duel).await;
                 let text = format!(
                     "{}Ничья\n",
                     text
                 );
                 bot.edit_message_text(tmp.chat.id, tmp.id, text).await?;
             }
-            Shoot::None => (),
+            Shoot::Round(_) | Shoot::None => (),
         }
     }
     Ok(())
@@ -0,0 +1,165 @@
+use crate::casino::{Payouts, SlotResult};
+use std::{cmp::Ordering, fmt};
+use teloxide::types::DiceEmoji;
+
+/// What a duel is played with and how a throw is scored.
+pub trait Game: fmt::Debug + Send + Sync {
+    fn emoji(&self) -> DiceEmoji;
+    /// Shown in the duel message, empty for plain dice.
+    fn name(&self) -> &'static str;
+    /// Points of a throw, more is better.
+    fn score(&self, value: i32) -> i32;
+    /// What the player did, e.g. `выбрасывает 5 очков`.
+    fn describe(&self, value: i32) -> String {
+        format!("набирает {} очков", self.score(value))
+    }
+    /// Who of the two won the round.
+    fn compare(&self, a: i32, b: i32) -> Ordering {
+        self.score(a).cmp(&self.score(b))
+    }
+}
+
+#[derive(Debug)]
+pub struct Dice;
+
+impl Game for Dice {
+    fn emoji(&self) -> DiceEmoji {
+        DiceEmoji::Dice
+    }
+    fn name(&self) -> &'static str {
+        ""
+    }
+    fn score(&self, value: i32) -> i32 {
+        value
+    }
+    fn describe(&self, value: i32) -> String {
+        format!("выбрасывает {value} очков")
+    }
+}
+
+/// 1 is a miss, 6 is the bullseye.
+#[derive(Debug)]
+pub struct Darts;
+
+impl Game for Darts {
+    fn emoji(&self) -> DiceEmoji {
+        DiceEmoji::Darts
+    }
+    fn name(&self) -> &'static str {
+        "🎯Дартс"
+    }
+    fn score(&self, value: i32) -> i32 {
+        value - 1
+    }
+    fn describe(&self, value: i32) -> String {
+        match value {
+            6 => "попадает в яблочко".to_string(),
+            1 => "промахивается".to_string(),
+            _ => format!("набирает {} очков", self.score(value)),
+        }
+    }
+}
+
+/// 4 and 5 go in.
+#[derive(Debug)]
+pub struct Basketball;
+
+impl Game for Basketball {
+    fn emoji(&self) -> DiceEmoji {
+        DiceEmoji::Basketball
+    }
+    fn name(&self) -> &'static str {
+        "🏀Баскетбол"
+    }
+    fn score(&self, value: i32) -> i32 {
+        (value >= 4) as i32
+    }
+    fn describe(&self, value: i32) -> String {
+        if self.score(value) > 0 {
+            "попадает"
+        } else {
+            "мажет"
+        }
+        .to_string()
+    }
+}
+
+/// 3, 4 and 5 are goals.
+#[derive(Debug)]
+pub struct Football;
+
+impl Game for Football {
+    fn emoji(&self) -> DiceEmoji {
+        DiceEmoji::Football
+    }
+    fn name(&self) -> &'static str {
+        "⚽️Футбол"
+    }
+    fn score(&self, value: i32) -> i32 {
+        (value >= 3) as i32
+    }
+    fn describe(&self, value: i32) -> String {
+        if self.score(value) > 0 {
+            "забивает"
+        } else {
+            "мажет"
+        }
+        .to_string()
+    }
+}
+
+/// Scored by knocked down pins, 6 is a strike.
+#[derive(Debug)]
+pub struct Bowling;
+
+impl Game for Bowling {
+    fn emoji(&self) -> DiceEmoji {
+        DiceEmoji::Bowling
+    }
+    fn name(&self) -> &'static str {
+        "🎳Боулинг"
+    }
+    fn score(&self, value: i32) -> i32 {
+        match value {
+            1 => 0,
+            2 => 1,
+            x => x,
+        }
+    }
+    fn describe(&self, value: i32) -> String {
+        match value {
+            6 => "выбивает страйк".to_string(),
+            1 => "промахивается".to_string(),
+            _ => format!("сбивает {} кеглей", self.score(value)),
+        }
+    }
+}
+
+/// Scored like a casino spin with the default payouts.
+#[derive(Debug)]
+pub struct SlotMachine;
+
+impl Game for SlotMachine {
+    fn emoji(&self) -> DiceEmoji {
+        DiceEmoji::SlotMachine
+    }
+    fn name(&self) -> &'static str {
+        "🎰Казино"
+    }
+    fn score(&self, value: i32) -> i32 {
+        SlotResult::from(value).payout(&Payouts::default()) as i32
+    }
+}
+
+/// Game named by a `/duel` argument.
+pub fn from_word(word: &str) -> Option<&'static dyn Game> {
+    Some(match word {
+        "dice" => &Dice,
+        "darts" => &Darts,
+        "basketball" => &Basketball,
+        "football" => &Football,
+        "bowling" => &Bowling,
+        "slot" => &SlotMachine,
+        _ => return None,
+    })
+}
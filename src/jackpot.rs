@@ -0,0 +1,78 @@
+use crate::utils::{read_from_file, write_to_file};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error};
+use teloxide::types::{ChatId, UserId};
+
+/// Last wins kept per chat.
+const HISTORY: usize = 10;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JackpotWin {
+    pub user: UserId,
+    pub user_name: String,
+    pub amount: usize,
+    pub date: DateTime<Utc>,
+}
+
+/// Points paid for spins in every chat, won all at once by the sevens triple.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Jackpot {
+    pots: BTreeMap<ChatId, usize>,
+    history: BTreeMap<ChatId, Vec<JackpotWin>>,
+}
+
+impl Jackpot {
+    pub fn try_new(s: &str) -> Result<Self, Box<dyn Error>> {
+        read_from_file(s)
+    }
+    pub fn save(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        write_to_file(s, self)
+    }
+    pub fn pot(&self, chat: ChatId) -> usize {
+        self.pots.get(&chat).copied().unwrap_or_default()
+    }
+    pub fn add(&mut self, chat: ChatId, amount: usize) {
+        *self.pots.entry(chat).or_default() += amount;
+    }
+
+    /// Empties the pot of the chat and writes the win down.
+    pub fn win(&mut self, chat: ChatId, user: UserId, user_name: &str) -> usize {
+        let amount = self.pots.remove(&chat).unwrap_or_default();
+        if amount == 0 {
+            return 0;
+        }
+        let history = self.history.entry(chat).or_default();
+        history.push(JackpotWin {
+            user,
+            user_name: user_name.to_string(),
+            amount,
+            date: Utc::now(),
+        });
+        if history.len() > HISTORY {
+            history.remove(0);
+        }
+        amount
+    }
+
+    pub fn text(&self, chat: ChatId) -> String {
+        let mut text = format!("💰Джекпот: {} очков\n", self.pot(chat));
+        let history = self
+            .history
+            .get(&chat)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if !history.is_empty() {
+            text += "\nПоследние победители:\n";
+        }
+        for x in history.iter().rev() {
+            text += &format!(
+                "{} — {} очков, {}\n",
+                x.user_name,
+                x.amount,
+                x.date.format("%d.%m.%Y")
+            );
+        }
+        text
+    }
+}
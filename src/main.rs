@@ -1,47 +1,116 @@
 use casino::{Record, SlotResult};
-use dptree::filter;
+use chats::Chats;
+use chatter::Chatter;
 use duel::callback_handler;
 use duel::Duel;
-use inline_python::python;
-use rand::Rng;
+use markov::{LogLine, Markov};
 use sedregex::find_and_replace;
-use serde_json::to_writer;
-use std::io::prelude::*;
 use std::{
-    collections::BTreeMap,
+    collections::HashSet,
     error::Error,
     sync::{Arc, Mutex},
 };
 use teloxide::{
     prelude::*,
-    types::{Dice, DiceEmoji::SlotMachine, MessageKind, Update, UserId},
+    types::{CallbackQuery, Dice, DiceEmoji::SlotMachine, MessageKind, ParseMode, Update, UserId},
     utils::command::BotCommands,
 };
 use utils::read_from_file;
 
 pub mod casino;
+pub mod chain;
+pub mod chats;
+pub mod chatter;
 pub mod duel;
 pub mod filters;
+pub mod games;
+pub mod jackpot;
+pub mod markov;
+pub mod mutes;
+pub mod royale;
+pub mod top;
+pub mod transfers;
 pub mod utils;
 
-type Casino = Arc<Mutex<BTreeMap<UserId, Record>>>;
-type MarkovModel = Arc<Mutex<inline_python::Context>>;
+type Casino = Arc<Mutex<casino::Records>>;
+type AJackpot = Arc<Mutex<jackpot::Jackpot>>;
+type ATransfers = Arc<Mutex<transfers::Transfers>>;
+type MarkovModel = Arc<Mutex<Markov>>;
+type NoImitate = Arc<Mutex<HashSet<UserId>>>;
 type ADuel = Arc<Mutex<Duel>>;
+type AChatter = Arc<Mutex<Chatter>>;
+type AChats = Arc<Mutex<Chats>>;
+type ALedger = Arc<Mutex<mutes::Ledger>>;
 // type RandomIter = Arc<Mutex<FnOnce>>;
 
 
-#[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 struct MyConfig {
     bot_token: String,
 
     bot_maintainer_id: u64,
     maintainer_useraname: String,
     casino_file: String,
+    jackpot_file: String,
+    // when the daily spins were given back last
+    reset_file: String,
+    transfers_file: String,
     messages_file: String,
+    markov_file: String,
+    no_imitate_file: String,
+    chatter_file: String,
     duel_file: String,
+    mutes_file: String,
 
+    // chat the data from before multi-chat support belongs to
     test_chat: i64,
+    // serve every chat except the denied ones instead of only the allowed ones
+    allow_all_chats: bool,
+    allowed_chats: Vec<i64>,
+    denied_chats: Vec<i64>,
+    chats_file: String,
 
+    markov_state_size: usize,
+    // chats sharing one model learned from all of them
+    markov_global_chats: Vec<i64>,
+    // messages a user must have written in the chat before `/say` imitates them
+    say_min_messages: usize,
+
+    // tables have to come after the plain values in toml
+    duel: duel::DuelSettings,
+    casino: casino::CasinoSettings,
+}
+
+impl Default for MyConfig {
+    fn default() -> Self {
+        Self {
+            bot_token: String::new(),
+            bot_maintainer_id: 0,
+            maintainer_useraname: String::new(),
+            casino_file: String::new(),
+            jackpot_file: String::from("jackpot.json"),
+            reset_file: String::from("reset.json"),
+            // the log is opened on start, so it needs a path that works out of the box
+            transfers_file: String::from("transfers.jsonl"),
+            messages_file: String::new(),
+            markov_file: String::from("markov.json"),
+            no_imitate_file: String::from("no_imitate.json"),
+            chatter_file: String::from("chatter.json"),
+            duel_file: String::new(),
+            mutes_file: String::from("mutes.json"),
+            test_chat: 0,
+            allow_all_chats: false,
+            allowed_chats: vec![],
+            denied_chats: vec![],
+            chats_file: String::from("chats.json"),
+            markov_state_size: 2,
+            markov_global_chats: vec![],
+            say_min_messages: 50,
+            duel: duel::DuelSettings::default(),
+            casino: casino::CasinoSettings::default(),
+        }
+    }
 }
 
 #[tokio::main]
@@ -51,66 +120,166 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let cfg: MyConfig = confy::load("ayabot", None)?;
     let bot = Bot::new(cfg.bot_token);
 
-    let casino: Casino = Arc::new(Mutex::new(read_from_file(&cfg.casino_file)?));
-    let messages = Arc::new(Mutex::new(
-        std::fs::File::options().append(true).open(&cfg.casino_file)?,
-    ));
-    let duel = Duel::try_new(&cfg.duel_file).unwrap();
+    let casino: Casino = Arc::new(Mutex::new(casino::load(
+        &cfg.casino_file,
+        ChatId(cfg.test_chat),
+    )?));
+    let duel = Duel::try_new(&cfg.duel_file, ChatId(cfg.test_chat), cfg.duel.clone()).unwrap();
     let aduel = Arc::new(Mutex::new(duel));
+    let ledger: ALedger = Arc::new(Mutex::new(mutes::Ledger::new(&cfg.mutes_file)));
+    let (sweeper_bot, sweeper_casino) = (bot.clone(), casino.clone());
+    let (duel_copy, ledger_copy) = (aduel.clone(), ledger.clone());
+    let sweeper = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            duel::sweep(&sweeper_bot, &sweeper_casino, &duel_copy, &ledger_copy).await;
+            royale::sweep(&sweeper_bot, &sweeper_casino, &duel_copy, &ledger_copy).await;
+            mutes::sweep(&sweeper_bot, &ledger_copy).await;
+        }
+    });
+    let mut chats: Chats = read_from_file(&cfg.chats_file).unwrap_or_default();
+    // 0 is the unset test chat, not a chat to serve
+    chats.set_config(
+        cfg.allowed_chats
+            .iter()
+            .chain([&cfg.test_chat])
+            .filter(|x| **x != 0)
+            .map(|x| ChatId(*x)),
+        cfg.denied_chats.iter().map(|x| ChatId(*x)),
+    );
+    chats.allow_all = cfg.allow_all_chats;
+    let chats: AChats = Arc::new(Mutex::new(chats));
+    let chatter: AChatter = Arc::new(Mutex::new(
+        Chatter::try_new(&cfg.chatter_file).unwrap_or_default(),
+    ));
 
     let parameters = ConfigParameters {
         bot_maintainer: UserId(cfg.bot_maintainer_id),
         maintainer_username: Some(cfg.maintainer_useraname),
+        no_imitate_file: cfg.no_imitate_file.clone(),
+        chatter_file: cfg.chatter_file.clone(),
+        chats_file: cfg.chats_file.clone(),
+        say_min_messages: cfg.say_min_messages,
+        casino: cfg.casino.clone(),
+        casino_file: cfg.casino_file.clone(),
+        jackpot_file: cfg.jackpot_file.clone(),
     };
+    let no_imitate: NoImitate = Arc::new(Mutex::new(
+        read_from_file(&cfg.no_imitate_file).unwrap_or_default(),
+    ));
 
-    let c = Arc::new(Mutex::new(inline_python::Context::new()));
-    let (context_copy, casino_copy) = (c.clone(), casino.clone());
-    println!("lol");
+    let markov = Markov::try_new(
+        &cfg.messages_file,
+        &cfg.markov_file,
+        cfg.markov_state_size,
+        cfg.markov_global_chats.iter().map(|x| ChatId(*x)).collect(),
+        ChatId(cfg.test_chat),
+    )?;
+    let c: MarkovModel = Arc::new(Mutex::new(markov));
+    let transfers: ATransfers = Arc::new(Mutex::new(transfers::Transfers::try_new(
+        &cfg.transfers_file,
+    )?));
+    let jackpot: AJackpot = Arc::new(Mutex::new(
+        jackpot::Jackpot::try_new(&cfg.jackpot_file).unwrap_or_default(),
+    ));
+    let (context_copy, casino_copy, jackpot_copy) = (c.clone(), casino.clone(), jackpot.clone());
+    let aduel_copy = aduel.clone();
+    let (jackpot_file, duel_file) = (cfg.jackpot_file.clone(), cfg.duel_file.clone());
+    let casino_file = cfg.casino_file.clone();
+    let (markov_file, markov_file_copy) = (cfg.markov_file.clone(), cfg.markov_file.clone());
 
+    // A reset missed while the bot was down happens once on start, one already done does not repeat.
+    let mut last_reset: chrono::DateTime<chrono::Utc> = match read_from_file(&cfg.reset_file) {
+        Ok(x) => x,
+        Err(_) => {
+            let x = cfg.casino.last_reset(chrono::Utc::now());
+            // written now, so a restart over the next reset does not skip it
+            if let Err(e) = utils::write_to_file(&cfg.reset_file, &x) {
+                log::warn!("failed to save last reset: {e}");
+            }
+            x
+        }
+    };
     let handle = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(86400));
         loop {
-            interval.tick().await;
-            let my_copy = casino_copy.clone();
-            to_writer(std::fs::File::create(&cfg.casino_file).unwrap(), &my_copy).unwrap();
-            casino::refresh_tries(my_copy);
-            let learn_text = std::fs::read_to_string(&cfg.messages_file).unwrap();
-            context_copy.lock().unwrap().run(python! {
-                import markovify
-                text_model = markovify.NewlineText('learn_text)
-            });
+            let now = chrono::Utc::now();
+            if last_reset < cfg.casino.last_reset(now) {
+                casino::refresh_tries(casino_copy.clone(), &cfg.casino);
+                // saved after the refresh, so a restart does not bring back yesterday's spins
+                if let Err(e) = utils::write_to_file(&cfg.casino_file, &casino_copy) {
+                    log::warn!("failed to save casino: {e}");
+                }
+                if let Err(e) = jackpot_copy.lock().unwrap().save(&cfg.jackpot_file) {
+                    log::warn!("failed to save jackpot: {e}");
+                }
+                if let Err(e) = aduel_copy.lock().unwrap().save(&cfg.duel_file) {
+                    log::warn!("failed to save duel records: {e}");
+                }
+                // the model learns on every message, only snapshot it for the next start
+                if let Err(e) = context_copy.lock().unwrap().save(&markov_file_copy) {
+                    log::warn!("failed to save markov snapshot: {e}");
+                }
+                last_reset = cfg.casino.last_reset(now);
+                if let Err(e) = utils::write_to_file(&cfg.reset_file, &last_reset) {
+                    log::warn!("failed to save last reset: {e}");
+                }
+            }
+            let next = cfg.casino.next_reset(chrono::Utc::now());
+            let wait = (next - chrono::Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
         }
     });
 
     let handler = dptree::entry()
+        // The maintainer is served everywhere, so a chat can be joined from inside it.
+        .branch(
+            Update::filter_message()
+                // Filter a maintainer by a used ID.
+                .filter(|msg: Message, cfg: ConfigParameters| {
+                    msg.from()
+                        .map(|user| user.id == cfg.bot_maintainer)
+                        .unwrap_or_default()
+                })
+                .filter_command::<MaintainerCommands>()
+                .endpoint(maintainer_commands_handler),
+        )
         .branch(Update::filter_message()
-        .filter(move |msg: Message| ChatId(cfg.test_chat) == msg.chat.id)
+        .filter(|msg: Message, chats: AChats| chats.lock().unwrap().is_allowed(msg.chat.id))
         .branch(
             dptree::entry()
                 // Filter commands: the next handlers will receive a parsed `SimpleCommand`.
                 .filter_command::<SimpleCommand>()
                 // If a command parsing fails, this handler will not be executed.
-                .endpoint(simple_commands_handler),
+                .branch(
+                    dptree::filter(|cmd: SimpleCommand| {
+                        matches!(
+                            cmd,
+                            SimpleCommand::Slot
+                                | SimpleCommand::Jackpot
+                                | SimpleCommand::Give { .. }
+                                | SimpleCommand::Tip { .. }
+                        )
+                    })
+                    .endpoint(casino_commands_handler),
+                )
+                .branch(dptree::endpoint(simple_commands_handler)),
         )
         .branch(
-            // Filter a maintainer by a used ID.
-            filter(|msg: Message, cfg: ConfigParameters| {
-                msg.from()
-                    .map(|user| user.id == cfg.bot_maintainer)
-                    .unwrap_or_default()
-            })
-            .filter_command::<MaintainerCommands>()
-            .endpoint(
-                |cmd: MaintainerCommands,
-                 casino: Casino| async move {
-                    match cmd {
-                        MaintainerCommands::Refresh => {
-                            casino::refresh_tries(casino.clone());
-                            Ok(())
-                        }
+            dptree::entry()
+                .filter_command::<AdminCommands>()
+                // Only chat admins may tune the bot for their chat.
+                .filter_async(|msg: Message, bot: Bot| async move {
+                    match msg.from() {
+                        Some(user) => bot
+                            .get_chat_member(msg.chat.id, user.id)
+                            .await
+                            .map(|x| x.is_privileged())
+                            .unwrap_or_default(),
+                        None => false,
                     }
-                },
-            ),
+                })
+                .endpoint(admin_commands_handler),
         )
         .branch(
             Message::filter_dice().endpoint(|msg: Message, _dice: Dice, bot: Bot| async move {
@@ -134,28 +303,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
             )
         .branch(
             dptree::entry().endpoint(
-                |bot: Bot, msg: Message, messages: Arc<Mutex<std::fs::File>>, context: MarkovModel| async move {
+                |bot: Bot, msg: Message, me: teloxide::types::Me, context: MarkovModel, chatter: AChatter| async move {
                     match msg.kind {
                         MessageKind::Common(_) => {
-                            let secret_number = rand::thread_rng().gen_range(1..10);
-                            if secret_number == 1 {
-                                let string = msg.text().unwrap_or_default();
-                                context.lock().unwrap().run(python! {
-                                    my_string = None
-                                    for word in 'string.split():
-                                        try:
-                                            my_string = text_model.make_sentence_with_start(word, strict=False)
-                                        except:
-                                            pass
-                                    if my_string == None:
-                                        my_string = ""
-                                });
-                                let text = context.lock().unwrap().get::<String>("my_string");
-                                bot.send_message(msg.chat.id, text).await?;
+                            let string = msg.text().unwrap_or_default();
+                            let mentioned = string.contains(&format!("@{}", me.username()))
+                                || msg
+                                    .reply_to_message()
+                                    .and_then(|x| x.from())
+                                    .map(|x| x.id == me.user.id)
+                                    .unwrap_or_default();
+                            if chatter.lock().unwrap().should_reply(msg.chat.id, string, mentioned, msg.date) {
+                                let text = context
+                                    .lock()
+                                    .unwrap()
+                                    .model(msg.chat.id)
+                                    .and_then(|x| x.make_sentence_with_any_start(string));
+                                if let Some(text) = text {
+                                    chatter.lock().unwrap().replied(msg.chat.id, msg.date);
+                                    bot.send_message(msg.chat.id, text).await?;
+                                }
+                            }
+                            if let Some(line) = msg.text() {
+                                let mut markov = context.lock().unwrap();
+                                if let Some(name) = msg.from().and_then(|x| x.username.as_ref()) {
+                                    markov.remember_name(name, msg.from().unwrap().id);
+                                }
+                                markov.learn(LogLine {
+                                    chat: msg.chat.id,
+                                    user: msg.from().map(|x| x.id),
+                                    date: Some(msg.date),
+                                    text: line.to_string(),
+                                })?;
                             }
-                            messages.lock().unwrap().write_all(
-                                ("\n".to_string() + msg.text().unwrap_or_default()).as_bytes(),
-                                )?;
                         }
                         _ => (),
                     }
@@ -163,14 +343,34 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 },
             ),
         ))
-        .branch(Update::filter_callback_query().endpoint(callback_handler));
+        .branch(
+            Update::filter_callback_query()
+                .branch(
+                    dptree::filter(|q: CallbackQuery| {
+                        q.data.as_deref().map(top::View::is_callback).unwrap_or_default()
+                    })
+                    .endpoint(top::callback_handler),
+                )
+                .branch(
+                    dptree::filter(|q: CallbackQuery| {
+                        q.data.as_deref().map(royale::is_callback).unwrap_or_default()
+                    })
+                    .endpoint(royale::callback_handler),
+                )
+                .branch(dptree::endpoint(callback_handler)),
+        );
     Dispatcher::builder(bot, handler)
         .dependencies(dptree::deps![
             parameters,
             casino.clone(),
-            messages.clone(),
             c.clone(),
-            aduel.clone()
+            no_imitate.clone(),
+            chatter.clone(),
+            chats.clone(),
+            aduel.clone(),
+            ledger.clone(),
+            jackpot.clone(),
+            transfers.clone()
         ])
         // If no handler succeeded to handle an update, this closure will be called.
         .default_handler(|upd| async move {
@@ -185,8 +385,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .dispatch()
         .await;
     handle.abort();
-    to_writer(std::fs::File::create("casinoo.txt").unwrap(), &casino)?;
-    aduel.clone().lock().unwrap().save("duel.txt");
+    sweeper.abort();
+    {
+        let mut casino = casino.lock().unwrap();
+        aduel.lock().unwrap().refund_open(&mut casino);
+    }
+    // balances and the pot are saved together, or paid spin costs would come back
+    if let Err(e) = utils::write_to_file(&casino_file, &casino) {
+        log::warn!("failed to save casino: {e}");
+    }
+    if let Err(e) = aduel.lock().unwrap().save(&duel_file) {
+        log::warn!("failed to save duel records: {e}");
+    }
+    if let Err(e) = c.lock().unwrap().save(&markov_file) {
+        log::warn!("failed to save markov snapshot: {e}");
+    }
+    if let Err(e) = jackpot.lock().unwrap().save(&jackpot_file) {
+        log::warn!("failed to save jackpot: {e}");
+    }
     Ok(())
 }
 
@@ -194,6 +410,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
 struct ConfigParameters {
     bot_maintainer: UserId,
     maintainer_username: Option<String>,
+    no_imitate_file: String,
+    chatter_file: String,
+    chats_file: String,
+    say_min_messages: usize,
+    casino: casino::CasinoSettings,
+    casino_file: String,
+    jackpot_file: String,
 }
 
 #[derive(BotCommands, Clone)]
@@ -205,12 +428,30 @@ enum SimpleCommand {
     Maintainer,
     #[command(description = "slot")]
     Slot,
-    #[command(description = "top")]
-    Top,
+    #[command(description = "jackpot pot and last winners")]
+    Jackpot,
+    #[command(description = "give casino points: /give @username N")]
+    Give { args: String },
+    #[command(description = "give casino points to the author of the replied message: /tip N")]
+    Tip { amount: String },
+    #[command(description = "top: /top [global] [casino|duel] [points|efficiency|spins]")]
+    Top { args: String },
     #[command(description = "generate")]
     Markov { string: String },
-    #[command(description = "duel")]
-    Duel { time: i64 },
+    #[command(description = "imitate someone: /say @user [word] or reply /say [word]")]
+    Say { args: String },
+    #[command(description = "forbid /say to imitate you (or allow again)")]
+    NoImitate,
+    #[command(description = "duel stats of you, @user or the replied user")]
+    DuelStats { user: String },
+    #[command(description = "duel leaderboard: /dueltop [global] [points|efficiency|spins]")]
+    DuelTop { args: String },
+    #[command(
+        description = "duel: /duel [minutes] [bo3|bo5] [sd] [dice|darts|basketball|football|bowling|slot] [betN]"
+    )]
+    Duel { args: String },
+    #[command(description = "battle royale: /royale [minutes] [game]")]
+    Royale { args: String },
 }
 
 #[derive(BotCommands, Clone)]
@@ -218,6 +459,289 @@ enum SimpleCommand {
 enum MaintainerCommands {
     #[command(parse_with = "split", description = "Обновить попытки")]
     Refresh,
+    #[command(description = "start working in a chat (this one if no id)")]
+    Join { chat: String },
+    #[command(description = "stop working in a chat and leave it (this one if no id)")]
+    Leave { chat: String },
+    #[command(description = "last point transfers (in a chat if an id is given)")]
+    Transfers { chat: String },
+}
+
+async fn maintainer_commands_handler(
+    msg: Message,
+    bot: Bot,
+    cmd: MaintainerCommands,
+    cfg: ConfigParameters,
+    casino: Casino,
+    chats: AChats,
+    transfers: ATransfers,
+) -> Result<(), teloxide::RequestError> {
+    let parse_chat = |x: String| x.trim().parse().map(ChatId).unwrap_or(msg.chat.id);
+    match cmd {
+        MaintainerCommands::Refresh => {
+            casino::refresh_tries(casino.clone(), &cfg.casino);
+        }
+        MaintainerCommands::Join { chat } => {
+            let chat = parse_chat(chat);
+            let mut chats = chats.lock().unwrap();
+            chats.join(chat);
+            if let Err(e) = chats.save(&cfg.chats_file) {
+                log::warn!("failed to save chats: {e}");
+            }
+        }
+        MaintainerCommands::Leave { chat } => {
+            let chat = parse_chat(chat);
+            {
+                let mut chats = chats.lock().unwrap();
+                chats.leave(chat);
+                if let Err(e) = chats.save(&cfg.chats_file) {
+                    log::warn!("failed to save chats: {e}");
+                }
+            }
+            if !chat.is_user() {
+                bot.leave_chat(chat).await?;
+            }
+        }
+        MaintainerCommands::Transfers { chat } => {
+            let chat = chat.trim().parse().map(ChatId).ok();
+            let text = transfers.lock().unwrap().audit(chat, 30);
+            bot.send_message(msg.chat.id, text).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "Admin commands")]
+enum AdminCommands {
+    #[command(description = "current reply settings")]
+    Chatter,
+    #[command(description = "chance to reply, in percent")]
+    Chance { percent: u8 },
+    #[command(description = "seconds between unsolicited replies")]
+    Cooldown { seconds: u32 },
+    #[command(description = "reply only when mentioned (toggle)")]
+    MentionOnly,
+    #[command(description = "words to always reply to, empty to clear")]
+    Keywords { words: String },
+    #[command(parse_with = "split", description = "quiet hours in UTC, /quiet 0 0 to disable")]
+    Quiet { from: u32, to: u32 },
+    #[command(description = "who is muted by the bot")]
+    Mutes,
+    #[command(description = "lift a mute, reply to the user or /pardon @username")]
+    Pardon { user: String },
+}
+
+async fn admin_commands_handler(
+    msg: Message,
+    bot: Bot,
+    cmd: AdminCommands,
+    cfg: ConfigParameters,
+    chatter: AChatter,
+    ledger: ALedger,
+    context: MarkovModel,
+) -> Result<(), teloxide::RequestError> {
+    let chat = msg.chat.id;
+    let file = &cfg.chatter_file;
+    let text = match cmd {
+        AdminCommands::Chatter => update_policy(&chatter, file, chat, |_| ()),
+        AdminCommands::Chance { percent } => update_policy(&chatter, file, chat, |policy| {
+            policy.probability = f64::from(percent.min(100)) / 100.0
+        }),
+        AdminCommands::Cooldown { seconds } => update_policy(&chatter, file, chat, |policy| {
+            policy.cooldown = seconds.into()
+        }),
+        AdminCommands::MentionOnly => update_policy(&chatter, file, chat, |policy| {
+            policy.only_mentioned = !policy.only_mentioned
+        }),
+        AdminCommands::Keywords { words } => update_policy(&chatter, file, chat, |policy| {
+            policy.keywords = words.split_whitespace().map(String::from).collect()
+        }),
+        AdminCommands::Quiet { from, to } => update_policy(&chatter, file, chat, |policy| {
+            policy.quiet_hours = (from != to).then_some((from % 24, to % 24))
+        }),
+        AdminCommands::Mutes => mutes::list(&ledger.lock().unwrap(), chat),
+        AdminCommands::Pardon { user } => {
+            let target = match msg.reply_to_message().and_then(|x| x.from()) {
+                Some(x) => Some(x.id),
+                None => context.lock().unwrap().find_user(user.trim()),
+            };
+            let mute = target.and_then(|x| ledger.lock().unwrap().pardon(chat, x));
+            match mute {
+                Some(x) => {
+                    mutes::lift(&bot, &x).await?;
+                    format!("🕊{} помилован", x.user_name)
+                }
+                None => "Этот пользователь не в муте".to_string(),
+            }
+        }
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+/// Changes the reply policy of `chat`, saves it and returns how it looks now.
+fn update_policy(
+    chatter: &AChatter,
+    file: &str,
+    chat: ChatId,
+    change: impl FnOnce(&mut chatter::ReplyPolicy),
+) -> String {
+    let mut chatter = chatter.lock().unwrap();
+    let policy = chatter.policy_mut(chat);
+    change(policy);
+    let text = policy.to_string();
+    if let Err(e) = chatter.save(file) {
+        log::warn!("failed to save reply settings: {e}");
+    }
+    text
+}
+
+/// `/slot`, `/jackpot`, `/give` and `/tip`, kept apart since an endpoint takes at most
+/// nine dependencies.
+#[allow(clippy::too_many_arguments)]
+async fn casino_commands_handler(
+    msg: Message,
+    bot: Bot,
+    cmd: SimpleCommand,
+    cfg: ConfigParameters,
+    casino: Casino,
+    context: MarkovModel,
+    jackpot: AJackpot,
+    transfers: ATransfers,
+) -> Result<(), teloxide::RequestError> {
+    if let SimpleCommand::Jackpot = cmd {
+        let text = jackpot.lock().unwrap().text(msg.chat.id);
+        bot.send_message(msg.chat.id, text).await?;
+        return Ok(());
+    }
+    let give = match &cmd {
+        SimpleCommand::Give { args } => {
+            let mut words = args.split_whitespace();
+            let to = words
+                .next()
+                .and_then(|x| context.lock().unwrap().find_user(x))
+                .map(|x| (x, None));
+            Some((to, words.next().and_then(|x| x.parse().ok())))
+        }
+        SimpleCommand::Tip { amount } => {
+            let to = msg.reply_to_message().and_then(|x| x.from());
+            if to.is_some_and(|x| x.is_bot) {
+                bot.send_message(msg.chat.id, "🤖Ботам очки не нужны")
+                    .reply_to_message_id(msg.id)
+                    .await?;
+                return Ok(());
+            }
+            let to = to.map(|x| (x.id, Some(x.full_name())));
+            Some((to, amount.trim().parse().ok()))
+        }
+        _ => None,
+    };
+    if let Some((to, amount)) = give {
+        let text = match (to, amount) {
+            (None, _) => "Кому? Ответь на сообщение или укажи @username".to_string(),
+            (_, None | Some(0)) => "Сколько? Укажи число очков".to_string(),
+            (Some((to, to_name)), Some(amount)) => {
+                let mut casino = casino.lock().unwrap();
+                let mut transfers = transfers.lock().unwrap();
+                match transfers::give(
+                    &mut transfers,
+                    &mut casino,
+                    &cfg.casino,
+                    msg.chat.id,
+                    msg.from().unwrap(),
+                    to,
+                    to_name,
+                    amount,
+                ) {
+                    Ok(x) => {
+                        // the log keeps every transfer, so the balances it changed are kept too
+                        if let Err(e) = utils::write_to_file(&cfg.casino_file, &*casino) {
+                            log::warn!("failed to save casino: {e}");
+                        }
+                        format!("💸{} переводит {} {} очков", x.from_name, x.to_name, x.amount)
+                    }
+                    Err(text) => text,
+                }
+            }
+        };
+        bot.send_message(msg.chat.id, text)
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(());
+    }
+    if casino
+        .lock()
+        .unwrap()
+        .get(&msg.chat.id)
+        .and_then(|x| x.get(&msg.from().unwrap().id))
+        .map(|x| x.tries_left == 0)
+        .unwrap_or(false)
+    {
+        bot.delete_message(msg.chat.id, msg.id).await?;
+        return Ok(());
+    }
+    let tmp = bot.send_dice(msg.chat.id).emoji(SlotMachine).await?;
+    let slot_result = SlotResult::from(match tmp.kind {
+        MessageKind::Dice(x) => x.dice.value,
+        _ => 0,
+    });
+    let rules = cfg.casino.rules(msg.chat.id);
+    let user_struct = msg.from().unwrap();
+    let user_id = user_struct.id;
+    let (won, paid, prize, pot) = {
+        let mut casino = casino.lock().unwrap();
+        let mut jackpot = jackpot.lock().unwrap();
+        let record = casino
+            .entry(msg.chat.id)
+            .or_default()
+            .entry(user_id)
+            .or_insert(Record::new(user_struct.full_name(), rules.daily_spins));
+        // players short of the cost spin for free, but only paid spins play for the pot
+        let paid = if record.points >= rules.spin_cost {
+            rules.spin_cost
+        } else {
+            0
+        };
+        record.points -= paid;
+        jackpot.add(msg.chat.id, paid);
+        let won = record.spin(slot_result, &rules);
+        let prize = if paid > 0 && slot_result == SlotResult::Sevens {
+            jackpot.win(msg.chat.id, user_id, &user_struct.full_name())
+        } else {
+            0
+        };
+        record.points += prize;
+        (won, paid, prize, jackpot.pot(msg.chat.id))
+    };
+    if prize > 0 {
+        if let Err(e) = jackpot.lock().unwrap().save(&cfg.jackpot_file) {
+            log::warn!("failed to save jackpot: {e}");
+        }
+    }
+    let tmpx = casino.lock().unwrap()[&msg.chat.id][&user_id].clone();
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "{} {}\nчисло спинов {} число очков {}{}",
+            String::from(slot_result),
+            if won > 0 { format!("+{won}") } else { String::new() },
+            tmpx.tries,
+            tmpx.points,
+            match (prize, rules.spin_cost, paid) {
+                (_, 0, _) => String::new(),
+                (_, _, 0) => format!("\n💰Джекпот: {pot}, бесплатный спин на него не играет"),
+                (0, _, _) => format!("\n💰Джекпот: {pot}"),
+                (x, _, _) => format!("\n🎉ДЖЕКПОТ! +{x}"),
+            }
+        ),
+    )
+    .reply_to_message_id(msg.id)
+    .await?;
+
+    bot.delete_message(tmp.chat.id, tmp.id).await?;
+    Ok(())
 }
 
 async fn simple_commands_handler(
@@ -228,97 +752,123 @@ async fn simple_commands_handler(
     me: teloxide::types::Me,
     casino: Casino,
     context: MarkovModel,
+    no_imitate: NoImitate,
     duel: ADuel,
 ) -> Result<(), teloxide::RequestError> {
-    if let SimpleCommand::Slot = cmd {
-        if casino
-            .lock()
-            .unwrap()
-            .get(&msg.from().unwrap().id)
-            .map(|x| x.tries_left == 0)
-            .unwrap_or(false)
-        {
-            bot.delete_message(msg.chat.id, msg.id).await?;
+    let top_args = match &cmd {
+        SimpleCommand::Top { args } => Some(args.clone()),
+        SimpleCommand::DuelTop { args } => Some(format!("duel {args}")),
+        _ => None,
+    };
+    if let Some(args) = top_args {
+        let (text, kbd) = top::render(
+            top::View::from_args(&args),
+            msg.chat.id,
+            &casino.lock().unwrap(),
+            &duel.lock().unwrap(),
+        );
+        bot.send_message(msg.chat.id, text)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(kbd)
+            .await?;
+        return Ok(());
+    }
+    if let SimpleCommand::Royale { args } = cmd {
+        let args = duel.lock().unwrap().parse_args(&args);
+        let host = msg.from().unwrap().to_owned();
+        let checked = duel.lock().unwrap().check_challenge(
+            msg.chat.id,
+            host.id,
+            None,
+            chrono::Utc::now(),
+        );
+        if let Err(text) = checked {
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .await?;
             return Ok(());
         }
-        let tmp = bot.send_dice(msg.chat.id).emoji(SlotMachine).await?;
-        let slot_result = SlotResult::from(match tmp.kind {
-            MessageKind::Dice(x) => x.dice.value,
-            _ => 0,
-        });
-        let user_struct = msg.from().unwrap();
-        let user_id = user_struct.id;
-        casino
+        let unmutable = !mutes::can_mute(&bot, msg.chat.id, me.id, host.id).await?;
+        let mymsg = bot
+            .send_message(msg.chat.id, "👑Королевская битва👑\nСобираем участников...")
+            .reply_markup(royale::join_keyboard())
+            .await?;
+        let text = duel
             .lock()
             .unwrap()
-            .entry(user_id)
-            .or_insert(Record::new(user_struct.full_name()))
-            .spin(slot_result);
-        let tmpx = casino.lock().unwrap().get(&user_id).unwrap().clone();
-        tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
-        bot.send_message(
-            msg.chat.id,
-            format!(
-                "{} \nчисло спинов {} число очков {}",
-                String::from(slot_result),
-                tmpx.tries,
-                tmpx.points
-            ),
-        )
-        .reply_to_message_id(msg.id)
-        .await?;
-
-        bot.delete_message(tmp.chat.id, tmp.id).await?;
+            .open_lobby(msg.chat.id, mymsg.id, &host, unmutable, args);
+        bot.edit_message_text(msg.chat.id, mymsg.id, text)
+            .reply_markup(royale::join_keyboard())
+            .await?;
         return Ok(());
     }
-    if let SimpleCommand::Duel { time } = cmd {
-        let time = match time {
-            16.. => 15,
-            2..=15 => time,
-            i64::MIN..=1 => 2,
-        };
+    if let SimpleCommand::Duel { args } = cmd {
+        let args = duel.lock().unwrap().parse_args(&args);
         let _pushkin = msg.from().unwrap().to_owned();
         let dantes = msg.reply_to_message();
         if dantes.is_none() {
             return Ok(());
         }
         let _dantes = dantes.unwrap().from().unwrap().to_owned();
+        let checked = duel.lock().unwrap().check_challenge(
+            msg.chat.id,
+            _pushkin.id,
+            Some(_dantes.id),
+            chrono::Utc::now(),
+        );
+        if let Err(text) = checked {
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .await?;
+            return Ok(());
+        }
+        let covered = {
+            let casino = casino.lock().unwrap();
+            casino::can_cover(&casino, msg.chat.id, _pushkin.id, args.stake)
+                && casino::can_cover(&casino, msg.chat.id, _dantes.id, args.stake)
+        };
+        if !covered {
+            bot.send_message(msg.chat.id, "💸Не у всех хватает очков на такую ставку")
+                .reply_to_message_id(msg.id)
+                .await?;
+            return Ok(());
+        }
+
+        // losers the bot cannot restrict pay a fallback penalty instead
+        let mut unmutable = vec![];
+        for user in [&_pushkin, &_dantes] {
+            if !mutes::can_mute(&bot, msg.chat.id, me.id, user.id).await? {
+                unmutable.push(user.id);
+            }
+        }
 
-        let cb = teloxide::types::InlineKeyboardButton::callback("шут", "шут");
-        let kbd = teloxide::types::InlineKeyboardMarkup::new(vec![vec![cb]]);
         let mymsg = bot
             .send_message(
                 msg.chat.id,
-                format!(
-                    "⚔️Дуэль между {} и {}⚔️\n⏱Ставка - {time} минут мута🙊\nБросайте кубики🎲\n",
-                    _pushkin.mention().unwrap(),
-                    _dantes.mention().unwrap()
-                ),
+                duel::challenge_text(&_pushkin, &_dantes, args, &unmutable),
             )
-            .reply_markup(kbd)
+            .reply_markup(duel::challenge_keyboard())
             .await?;
-        duel.lock().unwrap().start_duel(
-            _pushkin.id,
-            _dantes.id,
-            _pushkin.full_name(),
-            _dantes.full_name(),
-            mymsg.id,
-            time,
-        );
+        duel.lock()
+            .unwrap()
+            .start_duel(msg.chat.id, mymsg.id, &_pushkin, &_dantes, args, unmutable);
         return Ok(());
     }
     let text = match cmd {
         SimpleCommand::Help => {
             if msg.from().unwrap().id == cfg.bot_maintainer {
                 format!(
-                    "{}\n\n{}",
+                    "{}\n\n{}\n\n{}",
                     SimpleCommand::descriptions(),
+                    AdminCommands::descriptions(),
                     MaintainerCommands::descriptions()
                 )
             } else if msg.chat.is_group() || msg.chat.is_supergroup() {
-                SimpleCommand::descriptions()
-                    .username_from_me(&me)
-                    .to_string()
+                format!(
+                    "{}\n\n{}",
+                    SimpleCommand::descriptions().username_from_me(&me),
+                    AdminCommands::descriptions().username_from_me(&me)
+                )
             } else {
                 SimpleCommand::descriptions().to_string()
             }
@@ -333,43 +883,71 @@ async fn simple_commands_handler(
             }
         }
         SimpleCommand::Markov { string } => {
-            if !string.is_empty() {
-                context.lock().unwrap().run(python! {
-                    my_string = None
-                    for word in 'string.split():
-                        try:
-                            my_string = text_model.make_sentence_with_start(word, strict=False)
-                        except:
-                            pass
-                    if my_string == None:
-                        my_string = ""
-                });
-            } else if let Some(x) = msg.reply_to_message() {
-                let x = x.text().unwrap_or("lol").split(' ').last().unwrap();
-                context.lock().unwrap().run(python! {
-                    my_string = None
-                    for word in 'x.split():
-                        try:
-                            my_string = text_model.make_sentence_with_start(word, strict=False)
-                        except:
-                            pass
-                    if my_string == None:
-                        my_string = ""
-                });
-            } else {
-                context.lock().unwrap().run(python! {
-                    my_string = text_model.make_sentence()
-                    if my_string == None:
-                        my_string = ""
-                });
+            let markov = context.lock().unwrap();
+            markov
+                .model(msg.chat.id)
+                .and_then(|text_model| {
+                    if !string.is_empty() {
+                        text_model.make_sentence_with_any_start(&string)
+                    } else if let Some(x) = msg.reply_to_message() {
+                        let x = x.text().unwrap_or("lol").split(' ').last().unwrap();
+                        text_model.make_sentence_with_any_start(x)
+                    } else {
+                        text_model.make_sentence()
+                    }
+                })
+                .unwrap_or_else(|| "lol".to_string())
+        }
+        SimpleCommand::Say { args } => {
+            let markov = context.lock().unwrap();
+            let mut args = args.split_whitespace();
+            let target = match msg.reply_to_message().and_then(|x| x.from()) {
+                Some(user) => Some(user.id),
+                None => args.next().and_then(|name| markov.find_user(name)),
+            };
+            let text = match target {
+                None => "кого?".to_string(),
+                Some(user) if no_imitate.lock().unwrap().contains(&user) => {
+                    "он просил его не передразнивать".to_string()
+                }
+                Some(user) => match markov.user_model(msg.chat.id, user) {
+                    Some(text_model) if text_model.len() >= cfg.say_min_messages => {
+                        match args.next() {
+                            Some(word) => text_model.make_sentence_with_start(word, false),
+                            None => text_model.make_sentence(),
+                        }
+                        .unwrap_or_else(|| "lol".to_string())
+                    }
+                    _ => "он слишком мало писал".to_string(),
+                },
+            };
+            text
+        }
+        SimpleCommand::DuelStats { user } => {
+            let target = match msg.reply_to_message().and_then(|x| x.from()) {
+                Some(x) => Some(x.id),
+                None if user.trim().is_empty() => Some(msg.from().unwrap().id),
+                None => context.lock().unwrap().find_user(user.trim()),
+            };
+            let record = target.and_then(|x| duel.lock().unwrap().record(msg.chat.id, x));
+            match record {
+                Some(x) => x.to_string(),
+                None => "он ещё не дуэлился".to_string(),
             }
-            context.lock().unwrap().get::<String>("my_string")
         }
-        SimpleCommand::Top => {
-            let mut my_vec: Vec<Record> = casino.lock().unwrap().clone().into_values().collect();
-            my_vec.sort();
-            my_vec.reverse();
-            format!("{:?}", my_vec)
+        SimpleCommand::NoImitate => {
+            let user_id = msg.from().unwrap().id;
+            let mut no_imitate = no_imitate.lock().unwrap();
+            let text = if no_imitate.remove(&user_id) {
+                "теперь тебя можно передразнивать"
+            } else {
+                no_imitate.insert(user_id);
+                "больше не буду тебя передразнивать"
+            };
+            if let Err(e) = utils::write_to_file(&cfg.no_imitate_file, &*no_imitate) {
+                log::warn!("failed to save no imitate list: {e}");
+            }
+            text.to_string()
         }
         _ => "lol".to_string(),
     };
@@ -0,0 +1,204 @@
+use crate::{chain::Chain, utils::read_from_file};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::{prelude::*, BufWriter},
+};
+use teloxide::types::{ChatId, UserId};
+
+/// One line of the messages log.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLine {
+    pub chat: ChatId,
+    #[serde(default)]
+    pub user: Option<UserId>,
+    #[serde(default)]
+    pub date: Option<DateTime<Utc>>,
+    pub text: String,
+}
+
+/// Trained models of every author together with how much of the log they have seen.
+/// Chat and shared models are not stored, they are the sums of their authors' models.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<'a> {
+    state_size: usize,
+    log_len: u64,
+    users: Cow<'a, HashMap<ChatId, HashMap<UserId, Chain>>>,
+    anonymous: Cow<'a, HashMap<ChatId, Chain>>,
+    names: Cow<'a, HashMap<String, UserId>>,
+}
+
+/// Markov models of every chat, plus the shared one for chats that opted in.
+#[derive(Debug)]
+pub struct Markov {
+    state_size: usize,
+    log: File,
+    chats: HashMap<ChatId, Chain>,
+    // every user's messages in every chat, for `/say`
+    users: HashMap<ChatId, HashMap<UserId, Chain>>,
+    // messages without an author, mostly from the old log
+    anonymous: HashMap<ChatId, Chain>,
+    global: Chain,
+    global_chats: HashSet<ChatId>,
+    // lowercase usernames without the `@`
+    names: HashMap<String, UserId>,
+}
+
+impl Markov {
+    /// Starts from the snapshot in `snapshot` when it matches the settings and only
+    /// replays the part of the log written after it.
+    /// Plain text lines from before the log was per chat are learned by `legacy_chat`.
+    pub fn try_new(
+        s: &str,
+        snapshot: &str,
+        state_size: usize,
+        global_chats: HashSet<ChatId>,
+        legacy_chat: ChatId,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut log = File::options().append(true).create(true).open(s)?;
+        let text = std::fs::read_to_string(s)?;
+        // the old log has no newline after its last line, the next one must not be glued to it
+        if !text.is_empty() && !text.ends_with('\n') {
+            writeln!(log)?;
+        }
+        let snapshot = read_from_file::<_, Snapshot<'static>>(snapshot)
+            .ok()
+            .filter(|x| x.state_size == state_size && text.is_char_boundary(x.log_len as usize));
+        let mut markov = Self {
+            state_size,
+            log,
+            chats: HashMap::new(),
+            users: HashMap::new(),
+            anonymous: HashMap::new(),
+            global: Chain::new(state_size),
+            global_chats,
+            names: HashMap::new(),
+        };
+        let mut seen = 0;
+        if let Some(x) = snapshot {
+            markov.users = x.users.into_owned();
+            markov.anonymous = x.anonymous.into_owned();
+            markov.names = x.names.into_owned();
+            seen = x.log_len as usize;
+        }
+        let parse = |line: &str| {
+            serde_json::from_str(line).unwrap_or_else(|_| LogLine {
+                chat: legacy_chat,
+                user: None,
+                date: None,
+                text: line.to_string(),
+            })
+        };
+        // the snapshot has no corpus, the lines it already counts are only remembered
+        for line in text[..seen].lines().map(parse) {
+            markov.author_model(&line).remember_text(&line.text);
+        }
+        for line in text[seen..].lines().map(parse) {
+            markov.author_model(&line).feed_text(&line.text);
+        }
+        // chat and shared models are the sums of their authors' models
+        let chats: HashSet<ChatId> = markov
+            .users
+            .keys()
+            .chain(markov.anonymous.keys())
+            .copied()
+            .collect();
+        for chat in chats {
+            let mut chain = Chain::new(state_size);
+            if let Some(anonymous) = markov.anonymous.get(&chat) {
+                chain.merge(anonymous);
+            }
+            for user in markov.users.get(&chat).iter().flat_map(|x| x.values()) {
+                chain.merge(user);
+            }
+            if markov.global_chats.contains(&chat) {
+                markov.global.merge(&chain);
+            }
+            markov.chats.insert(chat, chain);
+        }
+        Ok(markov)
+    }
+
+    /// Appends the message to the log and teaches the chat's and the author's models.
+    pub fn learn(&mut self, line: LogLine) -> std::io::Result<()> {
+        writeln!(self.log, "{}", serde_json::to_string(&line)?)?;
+        self.feed(&line);
+        Ok(())
+    }
+
+    pub fn remember_name(&mut self, username: &str, id: UserId) {
+        self.names.insert(username.to_lowercase(), id);
+    }
+
+    /// Looks up a user seen before by `@username`.
+    pub fn find_user(&self, username: &str) -> Option<UserId> {
+        self.names
+            .get(&username.trim_start_matches('@').to_lowercase())
+            .copied()
+    }
+
+    pub fn user_model(&self, chat: ChatId, user: UserId) -> Option<&Chain> {
+        self.users.get(&chat)?.get(&user)
+    }
+
+    pub fn model(&self, chat: ChatId) -> Option<&Chain> {
+        if self.global_chats.contains(&chat) {
+            Some(&self.global)
+        } else {
+            self.chats.get(&chat)
+        }
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.log.sync_data()
+    }
+
+    pub fn save(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        self.sync()?;
+        let snapshot = Snapshot {
+            state_size: self.state_size,
+            log_len: self.log.metadata()?.len(),
+            users: Cow::Borrowed(&self.users),
+            anonymous: Cow::Borrowed(&self.anonymous),
+            names: Cow::Borrowed(&self.names),
+        };
+        // write next to the old snapshot first so a crash never leaves half a file
+        let tmp = format!("{s}.tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&tmp)?), &snapshot)?;
+        std::fs::rename(tmp, s)?;
+        Ok(())
+    }
+
+    fn feed(&mut self, line: &LogLine) {
+        let state_size = self.state_size;
+        self.author_model(line).feed_text(&line.text);
+        self.chats
+            .entry(line.chat)
+            .or_insert_with(|| Chain::new(state_size))
+            .feed_text(&line.text);
+        if self.global_chats.contains(&line.chat) {
+            self.global.feed_text(&line.text);
+        }
+    }
+
+    // the only model that keeps the line in the snapshot
+    fn author_model(&mut self, line: &LogLine) -> &mut Chain {
+        let state_size = self.state_size;
+        match line.user {
+            Some(user) => self
+                .users
+                .entry(line.chat)
+                .or_default()
+                .entry(user)
+                .or_insert_with(|| Chain::new(state_size)),
+            None => self
+                .anonymous
+                .entry(line.chat)
+                .or_insert_with(|| Chain::new(state_size)),
+        }
+    }
+}
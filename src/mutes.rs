@@ -0,0 +1,199 @@
+use crate::{
+    utils::{read_from_file, write_to_file},
+    ALedger,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    prelude::*,
+    types::{ChatId, ChatMemberKind, ChatPermissions, UserId},
+};
+
+/// A mute handed out by the bot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Mute {
+    pub chat: ChatId,
+    pub user: UserId,
+    pub user_name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub reason: String,
+    /// What the user could do before, given back when the mute ends.
+    pub permissions: ChatPermissions,
+}
+
+/// Mutes that are not lifted yet, saved on every change so a restart forgets none.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    file: String,
+    mutes: Vec<Mute>,
+}
+
+impl Ledger {
+    pub fn new(s: &str) -> Self {
+        Self {
+            file: s.to_string(),
+            mutes: read_from_file(s).unwrap_or_default(),
+        }
+    }
+    fn save(&self) {
+        if let Err(e) = write_to_file(&self.file, &self.mutes) {
+            log::warn!("failed to save mutes: {e}");
+        }
+    }
+    pub fn active(&self, chat: ChatId) -> Vec<&Mute> {
+        self.mutes.iter().filter(|x| x.chat == chat).collect()
+    }
+    pub fn muted(&self, chat: ChatId, user: UserId) -> Option<&Mute> {
+        self.mutes.iter().find(|x| x.chat == chat && x.user == user)
+    }
+
+    /// A second mute of a muted user extends the first one and keeps its permissions.
+    pub fn add(&mut self, mute: Mute) {
+        match self
+            .mutes
+            .iter_mut()
+            .find(|x| x.chat == mute.chat && x.user == mute.user)
+        {
+            Some(x) => {
+                x.end = x.end.max(mute.end);
+                x.reason = format!("{}, {}", x.reason, mute.reason);
+            }
+            None => self.mutes.push(mute),
+        }
+        self.save();
+    }
+
+    pub fn take_expired(&mut self, now: DateTime<Utc>) -> Vec<Mute> {
+        let (expired, rest) = std::mem::take(&mut self.mutes)
+            .into_iter()
+            .partition(|x| x.end <= now);
+        self.mutes = rest;
+        if !expired.is_empty() {
+            self.save();
+        }
+        expired
+    }
+
+    pub fn pardon(&mut self, chat: ChatId, user: UserId) -> Option<Mute> {
+        let i = self
+            .mutes
+            .iter()
+            .position(|x| x.chat == chat && x.user == user)?;
+        let mute = self.mutes.remove(i);
+        self.save();
+        Some(mute)
+    }
+}
+
+/// What `user` may do in `chat` right now: their own restrictions or the chat defaults.
+async fn permissions(bot: &Bot, chat: ChatId, user: UserId) -> ChatPermissions {
+    let defaults = bot
+        .get_chat(chat)
+        .await
+        .ok()
+        .and_then(|x| x.permissions())
+        .unwrap_or_else(ChatPermissions::all);
+    match bot.get_chat_member(chat, user).await.map(|x| x.kind) {
+        // members only carry their sending rights, the rest is the same for the whole chat
+        Ok(ChatMemberKind::Restricted(x)) => {
+            let mut permissions = defaults;
+            permissions.set(ChatPermissions::SEND_MESSAGES, x.can_send_messages);
+            permissions.set(
+                ChatPermissions::SEND_MEDIA_MESSAGES,
+                x.can_send_media_messages,
+            );
+            permissions.set(
+                ChatPermissions::SEND_OTHER_MESSAGES,
+                x.can_send_other_messages,
+            );
+            permissions.set(
+                ChatPermissions::ADD_WEB_PAGE_PREVIEWS,
+                x.can_add_web_page_previews,
+            );
+            permissions
+        }
+        _ => defaults,
+    }
+}
+
+/// Whether the bot may restrict `user`: it needs the right to, and admins are out of reach.
+pub async fn can_mute(
+    bot: &Bot,
+    chat: ChatId,
+    me: UserId,
+    user: UserId,
+) -> Result<bool, teloxide::RequestError> {
+    Ok(bot.get_chat_member(chat, me).await?.can_restrict_members()
+        && !bot.get_chat_member(chat, user).await?.is_privileged())
+}
+
+/// Mutes `user` for `minutes` and writes it down to be lifted later.
+pub async fn mute(
+    bot: &Bot,
+    ledger: &ALedger,
+    chat: ChatId,
+    user: UserId,
+    user_name: &str,
+    minutes: i64,
+    reason: &str,
+) -> Result<(), teloxide::RequestError> {
+    let before = ledger
+        .lock()
+        .unwrap()
+        .muted(chat, user)
+        .map(|x| (x.permissions, x.end));
+    let start = Utc::now();
+    let end = start + Duration::minutes(minutes);
+    // the ledger keeps the later end, telegram must not lift the mute before it
+    let (permissions, until) = match before {
+        Some((x, previous)) => (x, end.max(previous)),
+        None => (permissions(bot, chat, user).await, end),
+    };
+    bot.restrict_chat_member(chat, user, ChatPermissions::empty())
+        .until_date(until)
+        .await?;
+    ledger.lock().unwrap().add(Mute {
+        chat,
+        user,
+        user_name: user_name.to_string(),
+        start,
+        end,
+        reason: reason.to_string(),
+        permissions,
+    });
+    Ok(())
+}
+
+pub async fn lift(bot: &Bot, mute: &Mute) -> Result<(), teloxide::RequestError> {
+    bot.restrict_chat_member(mute.chat, mute.user, mute.permissions)
+        .await?;
+    Ok(())
+}
+
+/// Gives the permissions back to everybody whose mute is over.
+pub async fn sweep(bot: &Bot, ledger: &ALedger) {
+    let expired = ledger.lock().unwrap().take_expired(Utc::now());
+    for x in expired {
+        if let Err(e) = lift(bot, &x).await {
+            log::warn!("failed to unmute {}: {e}", x.user_name);
+        }
+    }
+}
+
+pub fn list(ledger: &Ledger, chat: ChatId) -> String {
+    let mutes = ledger.active(chat);
+    if mutes.is_empty() {
+        return "🙊Никто не в муте".to_string();
+    }
+    let mut text = String::from("🙊Сейчас в муте:\n");
+    for x in mutes {
+        text += &format!(
+            "{} до {} UTC — {}\n",
+            x.user_name,
+            x.end.format("%H:%M"),
+            x.reason
+        );
+    }
+    text
+}
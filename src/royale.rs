@@ -0,0 +1,349 @@
+use crate::{
+    duel::{game_line, mention, DuelArgs},
+    games::Game,
+    mutes, ADuel, ALedger, Casino,
+};
+use chrono::{DateTime, Duration, Utc};
+use teloxide::{
+    prelude::*,
+    types::{
+        CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageId, User, UserId,
+    },
+};
+
+const PREFIX: &str = "royale";
+const JOIN: &str = "royale join";
+const THROW: &str = "royale throw";
+
+#[derive(Debug)]
+pub struct Player {
+    pub id: UserId,
+    pub name: String,
+    pub mention: String,
+    pub throw: Option<i32>,
+    /// Pays the fallback penalty instead of a mute when losing.
+    pub unmutable: bool,
+}
+
+/// A duel for everybody who joins before the lobby closes, the lowest throw is muted.
+#[derive(Debug)]
+pub struct Lobby {
+    pub time: i64,
+    pub chat: ChatId,
+    pub msg: MessageId,
+    pub game: &'static dyn Game,
+    pub players: Vec<Player>,
+    /// Dice thrown in this battle, deleted once it is over.
+    pub dices: Vec<MessageId>,
+    /// End of joining before the start, end of throwing after it.
+    pub deadline: DateTime<Utc>,
+    pub started: bool,
+}
+
+/// What the sweeper has to tell the chat about a lobby.
+pub enum LobbyEvent {
+    Started(ChatId, MessageId, String),
+    Cancelled(Lobby),
+    Finished(Lobby, Vec<UserId>),
+}
+
+/// A throw in a battle, with the battle taken out once everybody threw.
+pub enum Throw {
+    Thrown(String),
+    Finished(Lobby, Vec<UserId>),
+    None,
+}
+
+impl Lobby {
+    pub fn new(
+        chat: ChatId,
+        msg: MessageId,
+        host: &User,
+        unmutable: bool,
+        args: DuelArgs,
+        join_time: Duration,
+    ) -> Self {
+        let mut lobby = Self {
+            time: args.time,
+            chat,
+            msg,
+            game: args.game,
+            players: vec![],
+            dices: vec![],
+            deadline: Utc::now() + join_time,
+            started: false,
+        };
+        lobby.join(host, unmutable);
+        lobby
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = format!(
+            "👑Королевская битва👑\n{}⏱Ставка - {} минут мута🙊\n",
+            game_line(self.game),
+            self.time
+        );
+        if !self.started {
+            text += "Участники:\n";
+            for x in &self.players {
+                let shield = if x.unmutable { "🛡" } else { "" };
+                text += &format!("{}{shield}\n", x.mention);
+            }
+            return text + "Жмите «вступить», пока набор открыт\n";
+        }
+        text += "Бросайте кубики🎲\n";
+        for x in &self.players {
+            match x.throw {
+                Some(value) => text += &format!("{} {}\n", x.mention, self.game.describe(value)),
+                None => text += &format!("{} ещё не бросил\n", x.mention),
+            }
+        }
+        text
+    }
+
+    pub fn results(&self, losers: &[UserId]) -> String {
+        let mut text = self.text();
+        if losers.is_empty() {
+            return text + "Ничья, все выжили\n";
+        }
+        for x in self
+            .players
+            .iter()
+            .filter(|x| losers.contains(&x.id) && !x.unmutable)
+        {
+            text += &format!("{} отправляется в бан☠️\n", x.name);
+        }
+        text
+    }
+
+    pub fn join(&mut self, user: &User, unmutable: bool) -> bool {
+        if self.started || self.players.iter().any(|x| x.id == user.id) {
+            return false;
+        }
+        self.players.push(Player {
+            id: user.id,
+            name: user.full_name(),
+            mention: mention(user),
+            throw: None,
+            unmutable,
+        });
+        true
+    }
+
+    pub fn can_throw(&self, id: UserId) -> bool {
+        self.started && self.players.iter().any(|x| x.id == id && x.throw.is_none())
+    }
+
+    pub fn set_value(&mut self, id: UserId, value: i32) {
+        if let Some(x) = self.players.iter_mut().find(|x| x.id == id) {
+            x.throw = Some(value);
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.players.iter().all(|x| x.throw.is_some())
+    }
+
+    /// Whoever did not throw loses, otherwise everybody with the lowest throw.
+    /// Nobody loses when all throws are equal.
+    pub fn losers(&self) -> Vec<UserId> {
+        let missing: Vec<UserId> = self
+            .players
+            .iter()
+            .filter(|x| x.throw.is_none())
+            .map(|x| x.id)
+            .collect();
+        if !missing.is_empty() {
+            return missing;
+        }
+        let throws: Vec<(UserId, i32)> = self
+            .players
+            .iter()
+            .filter_map(|x| Some((x.id, x.throw?)))
+            .collect();
+        let lowest = match throws
+            .iter()
+            .map(|x| x.1)
+            .min_by(|a, b| self.game.compare(*a, *b))
+        {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let losers: Vec<UserId> = throws
+            .iter()
+            .filter(|x| self.game.compare(x.1, lowest).is_eq())
+            .map(|x| x.0)
+            .collect();
+        if losers.len() == throws.len() {
+            vec![]
+        } else {
+            losers
+        }
+    }
+}
+
+pub fn is_callback(data: &str) -> bool {
+    data.split(' ').next() == Some(PREFIX)
+}
+
+pub fn join_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("вступить", JOIN)]])
+}
+
+fn throw_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("бросить", THROW)]])
+}
+
+async fn finish(
+    bot: &Bot,
+    casino: &Casino,
+    duel: &ADuel,
+    ledger: &ALedger,
+    lobby: Lobby,
+    losers: Vec<UserId>,
+) {
+    for x in &lobby.dices {
+        if let Err(e) = bot.delete_message(lobby.chat, *x).await {
+            log::warn!("failed to delete battle dice: {e}");
+        }
+    }
+    let (muted, fined): (Vec<&Player>, Vec<&Player>) = lobby
+        .players
+        .iter()
+        .filter(|x| losers.contains(&x.id))
+        .partition(|x| !x.unmutable);
+    let fines: String = {
+        let mut casino = casino.lock().unwrap();
+        let mut duel = duel.lock().unwrap();
+        fined
+            .iter()
+            .map(|x| duel.fine(&mut casino, lobby.chat, x.id))
+            .collect()
+    };
+    if let Err(e) = bot
+        .edit_message_text(lobby.chat, lobby.msg, lobby.results(&losers) + &fines)
+        .await
+    {
+        log::warn!("failed to finish battle: {e}");
+    }
+    for x in muted {
+        if let Err(e) = mutes::mute(
+            bot,
+            ledger,
+            lobby.chat,
+            x.id,
+            &x.name,
+            lobby.time,
+            "👑Проиграл королевскую битву",
+        )
+        .await
+        {
+            log::warn!("failed to mute battle loser: {e}");
+        }
+    }
+}
+
+/// Starts the lobbies that stopped taking players and ends the battles that ran out of time.
+pub async fn sweep(bot: &Bot, casino: &Casino, duel: &ADuel, ledger: &ALedger) {
+    let events = duel.lock().unwrap().take_lobbies(Utc::now());
+    for x in events {
+        let result = match x {
+            LobbyEvent::Started(chat, msg, text) => bot
+                .edit_message_text(chat, msg, text)
+                .reply_markup(throw_keyboard())
+                .await
+                .map(|_| ()),
+            LobbyEvent::Cancelled(lobby) => bot
+                .edit_message_text(
+                    lobby.chat,
+                    lobby.msg,
+                    "👑Королевская битва👑\n⌛Не набралось участников, битва отменена\n",
+                )
+                .await
+                .map(|_| ()),
+            LobbyEvent::Finished(lobby, losers) => {
+                finish(bot, casino, duel, ledger, lobby, losers).await;
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("failed to update battle: {e}");
+        }
+    }
+}
+
+pub async fn callback_handler(
+    bot: Bot,
+    q: CallbackQuery,
+    me: teloxide::types::Me,
+    casino: Casino,
+    duel: ADuel,
+    ledger: ALedger,
+) -> Result<(), teloxide::RequestError> {
+    bot.answer_callback_query(&q.id).await?;
+    let (chat_id, msg_id) = match &q.message {
+        Some(x) => (x.chat.id, x.id),
+        None => return Ok(()),
+    };
+    match q.data.as_deref() {
+        Some(JOIN) => {
+            let unmutable = !mutes::can_mute(&bot, chat_id, me.id, q.from.id).await?;
+            let text = duel
+                .lock()
+                .unwrap()
+                .join_lobby(chat_id, msg_id, &q.from, unmutable);
+            match text {
+                Some(Ok(text)) => {
+                    bot.edit_message_text(chat_id, msg_id, text)
+                        .reply_markup(join_keyboard())
+                        .await?;
+                }
+                Some(Err(text)) => {
+                    bot.send_message(chat_id, text)
+                        .reply_to_message_id(msg_id)
+                        .await?;
+                }
+                None => (),
+            }
+        }
+        Some(THROW) => {
+            let game = duel.lock().unwrap().lobby_game(chat_id, msg_id, q.from.id);
+            let game = match game {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            if bot
+                .get_chat_member(chat_id, q.from.id)
+                .await?
+                .is_restricted()
+            {
+                return Ok(());
+            }
+            let dice = bot.send_dice(chat_id).emoji(game.emoji()).await?;
+            let value = match dice.kind {
+                teloxide::types::MessageKind::Dice(x) => x.dice.value,
+                _ => 0,
+            };
+            let throw = duel
+                .lock()
+                .unwrap()
+                .lobby_throw(chat_id, msg_id, q.from.id, dice.id, value);
+
+            // let the dice finish rolling before telling the result
+            tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
+            match throw {
+                Throw::Thrown(text) => {
+                    bot.edit_message_text(chat_id, msg_id, text)
+                        .reply_markup(throw_keyboard())
+                        .await?;
+                }
+                Throw::Finished(lobby, losers) => {
+                    finish(&bot, &casino, &duel, &ledger, lobby, losers).await
+                }
+                Throw::None => (),
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
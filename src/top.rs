@@ -0,0 +1,282 @@
+use crate::{casino::Record, duel::Duel, ADuel, Casino};
+use std::cmp::Reverse;
+use teloxide::{
+    prelude::*,
+    types::{CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, UserId},
+    utils::html,
+};
+
+const PAGE_SIZE: usize = 10;
+const PREFIX: &str = "top";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Board {
+    Casino,
+    Duel,
+}
+
+/// For duels points are wins, efficiency is the win rate and spins are duels fought.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    Points,
+    Efficiency,
+    Spins,
+}
+
+/// One page of a leaderboard, also stored in the callback data of its buttons.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct View {
+    pub board: Board,
+    pub sort: Sort,
+    pub global: bool,
+    pub page: usize,
+}
+
+impl View {
+    /// Parses `/top` arguments in any order, e.g. `/top global duel efficiency`.
+    pub fn from_args(args: &str) -> Self {
+        let mut view = Self {
+            board: Board::Casino,
+            sort: Sort::Points,
+            global: false,
+            page: 0,
+        };
+        for word in args.split_whitespace() {
+            match word {
+                "global" => view.global = true,
+                "casino" => view.board = Board::Casino,
+                "duel" => view.board = Board::Duel,
+                "points" => view.sort = Sort::Points,
+                "efficiency" => view.sort = Sort::Efficiency,
+                "spins" => view.sort = Sort::Spins,
+                _ => (),
+            }
+        }
+        view
+    }
+
+    pub fn is_callback(data: &str) -> bool {
+        data.split(' ').next() == Some(PREFIX)
+    }
+
+    fn to_data(self) -> String {
+        let board = match self.board {
+            Board::Casino => "casino",
+            Board::Duel => "duel",
+        };
+        let sort = match self.sort {
+            Sort::Points => "points",
+            Sort::Efficiency => "efficiency",
+            Sort::Spins => "spins",
+        };
+        let scope = if self.global { "global" } else { "chat" };
+        format!("{PREFIX} {board} {sort} {scope} {}", self.page)
+    }
+
+    fn from_data(data: &str) -> Option<Self> {
+        let mut words = data.split(' ');
+        if words.next() != Some(PREFIX) {
+            return None;
+        }
+        let mut view = Self::from_args(&words.clone().take(3).collect::<Vec<_>>().join(" "));
+        view.page = words.nth(3)?.parse().ok()?;
+        Some(view)
+    }
+}
+
+fn mention(id: UserId, name: &str) -> String {
+    format!("<a href=\"tg://user?id={id}\">{}</a>", html::escape(name))
+}
+
+fn casino_lines(mut records: Vec<(UserId, Record)>, sort: Sort) -> Vec<String> {
+    match sort {
+        Sort::Points => records.sort_by_key(|x| Reverse(x.1.points)),
+        Sort::Efficiency => records.sort_by(|a, b| b.1.efficiency().total_cmp(&a.1.efficiency())),
+        Sort::Spins => records.sort_by_key(|x| Reverse(x.1.tries)),
+    }
+    records
+        .iter()
+        .map(|(id, x)| {
+            format!(
+                "{} — {} очков, {} спинов, {:.2} за спин",
+                mention(*id, &x.user_name),
+                x.points,
+                x.tries,
+                x.efficiency()
+            )
+        })
+        .collect()
+}
+
+fn duel_lines(duel: &Duel, chat: ChatId, view: View) -> Vec<String> {
+    let mut records = if view.global {
+        duel.global_records()
+    } else {
+        duel.records(chat)
+    };
+    match view.sort {
+        Sort::Points => records.sort_by_key(|x| Reverse(x.1.win)),
+        Sort::Efficiency => records.sort_by(|a, b| b.1.win_rate().total_cmp(&a.1.win_rate())),
+        Sort::Spins => records.sort_by_key(|x| Reverse(x.1.win + x.1.lose)),
+    }
+    records
+        .iter()
+        .map(|(id, x)| {
+            format!(
+                "{} — {} побед, {} поражений, {:.0}% побед, лучшая серия {}",
+                mention(*id, &x.user_name),
+                x.win,
+                x.lose,
+                x.win_rate() * 100.0,
+                x.best_streak
+            )
+        })
+        .collect()
+}
+
+/// Html text of the page and the keyboard to move to the other pages and sorts.
+pub fn render(
+    view: View,
+    chat: ChatId,
+    casino: &crate::casino::Records,
+    duel: &Duel,
+) -> (String, InlineKeyboardMarkup) {
+    let lines = match view.board {
+        Board::Casino if view.global => {
+            casino_lines(crate::casino::global_records(casino), view.sort)
+        }
+        Board::Casino => casino_lines(
+            casino
+                .get(&chat)
+                .map(|x| x.iter().map(|(id, x)| (*id, x.clone())).collect())
+                .unwrap_or_default(),
+            view.sort,
+        ),
+        Board::Duel => duel_lines(duel, chat, view),
+    };
+    let pages = lines.len().div_ceil(PAGE_SIZE).max(1);
+    let view = View {
+        page: view.page.min(pages - 1),
+        ..view
+    };
+
+    let title = match (view.board, view.sort) {
+        (Board::Casino, Sort::Points) => "🎰Топ казино по очкам",
+        (Board::Casino, Sort::Efficiency) => "🎰Топ казино по очкам за спин",
+        (Board::Casino, Sort::Spins) => "🎰Топ казино по спинам",
+        (Board::Duel, Sort::Points) => "⚔️Топ дуэлянтов по победам",
+        (Board::Duel, Sort::Efficiency) => "⚔️Топ дуэлянтов по проценту побед",
+        (Board::Duel, Sort::Spins) => "⚔️Топ дуэлянтов по числу дуэлей",
+    };
+    let mut text = format!(
+        "{title}{}\n\n",
+        if view.global {
+            " (все чаты)"
+        } else {
+            ""
+        }
+    );
+    if lines.is_empty() {
+        text += "пока никого нет";
+    }
+    for (i, line) in lines
+        .iter()
+        .enumerate()
+        .skip(view.page * PAGE_SIZE)
+        .take(PAGE_SIZE)
+    {
+        text += &format!("{}. {line}\n", i + 1);
+    }
+    if pages > 1 {
+        text += &format!("\nстраница {}/{pages}", view.page + 1);
+    }
+
+    let button = |label: &str, x: View| InlineKeyboardButton::callback(label, x.to_data());
+    let mut pager = vec![];
+    if view.page > 0 {
+        pager.push(button(
+            "◀️",
+            View {
+                page: view.page - 1,
+                ..view
+            },
+        ));
+    }
+    if view.page + 1 < pages {
+        pager.push(button(
+            "▶️",
+            View {
+                page: view.page + 1,
+                ..view
+            },
+        ));
+    }
+    let (points, efficiency, spins) = match view.board {
+        Board::Casino => ("очки", "за спин", "спины"),
+        Board::Duel => ("победы", "процент", "дуэли"),
+    };
+    let sorts = [
+        (points, Sort::Points),
+        (efficiency, Sort::Efficiency),
+        (spins, Sort::Spins),
+    ]
+    .into_iter()
+    .filter(|(_, sort)| *sort != view.sort)
+    .map(|(label, sort)| {
+        button(
+            label,
+            View {
+                sort,
+                page: 0,
+                ..view
+            },
+        )
+    })
+    .collect();
+    let other = match view.board {
+        Board::Casino => button(
+            "⚔️дуэли",
+            View {
+                board: Board::Duel,
+                page: 0,
+                ..view
+            },
+        ),
+        Board::Duel => button(
+            "🎰казино",
+            View {
+                board: Board::Casino,
+                page: 0,
+                ..view
+            },
+        ),
+    };
+    let kbd = InlineKeyboardMarkup::new(
+        [pager, sorts, vec![other]]
+            .into_iter()
+            .filter(|x: &Vec<_>| !x.is_empty()),
+    );
+    (text, kbd)
+}
+
+pub async fn callback_handler(
+    bot: Bot,
+    q: CallbackQuery,
+    casino: Casino,
+    duel: ADuel,
+) -> Result<(), teloxide::RequestError> {
+    bot.answer_callback_query(&q.id).await?;
+    if let (Some(view), Some(msg)) = (q.data.as_deref().and_then(View::from_data), &q.message) {
+        let (text, kbd) = render(
+            view,
+            msg.chat.id,
+            &casino.lock().unwrap(),
+            &duel.lock().unwrap(),
+        );
+        bot.edit_message_text(msg.chat.id, msg.id, text)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(kbd)
+            .await?;
+    }
+    Ok(())
+}
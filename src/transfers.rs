@@ -0,0 +1,133 @@
+use crate::casino::{self, CasinoSettings, Record, Records};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fs::File, io::prelude::*};
+use teloxide::types::{ChatId, User, UserId};
+
+/// Points one player gave another.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transfer {
+    pub chat: ChatId,
+    pub from: UserId,
+    pub from_name: String,
+    pub to: UserId,
+    pub to_name: String,
+    pub amount: usize,
+    pub date: DateTime<Utc>,
+}
+
+/// Every transfer ever made, kept in memory and appended to a JSON lines log.
+#[derive(Debug)]
+pub struct Transfers {
+    log: File,
+    transfers: Vec<Transfer>,
+}
+
+impl Transfers {
+    pub fn try_new(s: &str) -> Result<Self, Box<dyn Error>> {
+        let log = File::options().append(true).create(true).open(s)?;
+        let transfers = std::fs::read_to_string(s)?
+            .lines()
+            .filter_map(|x| serde_json::from_str(x).ok())
+            .collect();
+        Ok(Self { log, transfers })
+    }
+
+    pub fn record(&mut self, transfer: Transfer) -> std::io::Result<()> {
+        writeln!(self.log, "{}", serde_json::to_string(&transfer)?)?;
+        self.transfers.push(transfer);
+        Ok(())
+    }
+
+    /// Points `user` gave away in `chat` from `since` on.
+    pub fn sent_since(&self, chat: ChatId, user: UserId, since: DateTime<Utc>) -> usize {
+        self.transfers
+            .iter()
+            .rev()
+            .take_while(|x| x.date >= since)
+            .filter(|x| x.chat == chat && x.from == user)
+            .map(|x| x.amount)
+            .sum()
+    }
+
+    /// The last `n` transfers, in `chat` only if given.
+    pub fn audit(&self, chat: Option<ChatId>, n: usize) -> String {
+        let mut lines: Vec<String> = self
+            .transfers
+            .iter()
+            .rev()
+            .filter(|x| chat.is_none_or(|chat| x.chat == chat))
+            .take(n)
+            .map(|x| {
+                format!(
+                    "{} [{}] {} ({}) → {} ({}): {}",
+                    x.date.format("%d.%m %H:%M"),
+                    x.chat,
+                    x.from_name,
+                    x.from,
+                    x.to_name,
+                    x.to,
+                    x.amount
+                )
+            })
+            .collect();
+        if lines.is_empty() {
+            return "переводов не было".to_string();
+        }
+        lines.reverse();
+        lines.join("\n")
+    }
+}
+
+/// Moves points between players of `chat` after checking the balance and the daily
+/// limit, or tells why it cannot. Receivers without a record need `to_name` to get one.
+#[allow(clippy::too_many_arguments)]
+pub fn give(
+    transfers: &mut Transfers,
+    casino: &mut Records,
+    settings: &CasinoSettings,
+    chat: ChatId,
+    from: &User,
+    to: UserId,
+    to_name: Option<String>,
+    amount: usize,
+) -> Result<Transfer, String> {
+    if from.id == to {
+        return Err("Себе переводить нельзя".to_string());
+    }
+    let rules = settings.rules(chat);
+    let now = Utc::now();
+    let sent = transfers.sent_since(chat, from.id, settings.last_reset(now));
+    if sent.saturating_add(amount) > rules.transfer_limit {
+        return Err(format!(
+            "💸Лимит переводов на сегодня, можно ещё {} очков",
+            rules.transfer_limit.saturating_sub(sent)
+        ));
+    }
+    if !casino::can_cover(casino, chat, from.id, amount) {
+        return Err("💸Не хватает очков".to_string());
+    }
+    let records = casino.entry(chat).or_default();
+    let to_name = match (records.get(&to), to_name) {
+        (Some(x), _) => x.user_name.clone(),
+        (None, Some(name)) => {
+            records.insert(to, Record::new(name.clone(), rules.daily_spins));
+            name
+        }
+        (None, None) => return Err("Он ещё не играл в казино".to_string()),
+    };
+    let amount = casino::transfer(casino, chat, from.id, to, amount);
+    let transfer = Transfer {
+        chat,
+        from: from.id,
+        from_name: from.full_name(),
+        to,
+        to_name,
+        amount,
+        date: now,
+    };
+    if let Err(e) = transfers.record(transfer.clone()) {
+        log::warn!("failed to log transfer: {e}");
+    }
+    Ok(transfer)
+}
@@ -13,3 +13,13 @@ pub fn read_from_file<P: AsRef<Path>, T: for<'de> serde::de::Deserialize<'de>>(
     // Return the `User`.
     Ok(u)
 }
+
+/// Writes `value` as JSON to `path`, replacing what was there.
+pub fn write_to_file<P: AsRef<Path>, T: serde::Serialize>(
+    path: P,
+    value: &T,
+) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    serde_json::to_writer(file, value)?;
+    Ok(())
+}